@@ -0,0 +1,167 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/4/21
+*/
+//!
+//! 生成以大结果集为主的离线抓包文件, 用于比较不同版本解析结果集的速度
+//!
+//! cargo run --release --example resultset_pcap -- /tmp/resultset.pcap
+//! target/release/testaa -r /tmp/resultset.pcap -p 3306 > /dev/null
+//!
+//! 运行结束时输出的elapsed、packets/s即为结果, 参数可选: 连接数 每个连接的查询数 每个结果集的行数 每行字节数
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+const MSS: usize = 1448;
+const SERVER: [u8; 4] = [10, 0, 0, 1];
+const SERVER_PORT: u16 = 3306;
+const COLUMNS: usize = 4;
+
+///
+/// pcap文件格式的写入, 链路层为以太网
+struct PcapWriter {
+    out: BufWriter<File>,
+    ts: u64,                                    // 微秒
+    packets: u64,
+}
+
+impl PcapWriter {
+    fn new(path: &str) -> Result<PcapWriter, Box<dyn Error>> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&4u16.to_le_bytes())?;
+        out.write_all(&[0u8; 8])?;
+        out.write_all(&65535u32.to_le_bytes())?;
+        out.write_all(&1u32.to_le_bytes())?;
+        Ok(PcapWriter { out, ts: 1_587_427_200_000_000, packets: 0 })
+    }
+
+    fn write(&mut self, frame: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.ts += 10;
+        self.packets += 1;
+        self.out.write_all(&((self.ts / 1_000_000) as u32).to_le_bytes())?;
+        self.out.write_all(&((self.ts % 1_000_000) as u32).to_le_bytes())?;
+        self.out.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(frame)?;
+        Ok(())
+    }
+}
+
+///
+/// 单方向的tcp数据流, 按MSS切分为多个分段
+struct Flow {
+    source: [u8; 4],
+    destination: [u8; 4],
+    source_port: u16,
+    destination_port: u16,
+    seq: u32,
+}
+
+impl Flow {
+    fn send(&mut self, writer: &mut PcapWriter, data: &[u8], ack: u32) -> Result<(), Box<dyn Error>> {
+        for chunk in data.chunks(MSS) {
+            writer.write(&self.frame(chunk, ack))?;
+            self.seq = self.seq.wrapping_add(chunk.len() as u32);
+        }
+        Ok(())
+    }
+
+    fn frame(&self, payload: &[u8], ack: u32) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(54 + payload.len());
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02, 0x08, 0x00]);
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&((40 + payload.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 1, 0x40, 0, 64, 6, 0, 0]);
+        frame.extend_from_slice(&self.source);
+        frame.extend_from_slice(&self.destination);
+        frame.extend_from_slice(&self.source_port.to_be_bytes());
+        frame.extend_from_slice(&self.destination_port.to_be_bytes());
+        frame.extend_from_slice(&self.seq.to_be_bytes());
+        frame.extend_from_slice(&ack.to_be_bytes());
+        frame.extend_from_slice(&[0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+}
+
+///
+/// 加上4字节包头的mysql物理包
+fn mysql_packet(out: &mut Vec<u8>, seq_id: u8, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes()[..3]);
+    out.push(seq_id);
+    out.extend_from_slice(payload);
+}
+
+///
+/// 文本协议的结果集: 列数、列定义、EOF、行数据、EOF
+fn result_set(rows: usize, row_len: usize) -> Vec<u8> {
+    let mut out = vec![];
+    let mut seq_id = 1u8;
+    mysql_packet(&mut out, seq_id, &[COLUMNS as u8]);
+    for i in 0..COLUMNS {
+        seq_id = seq_id.wrapping_add(1);
+        let name = format!("c{}", i);
+        let mut def = vec![3];
+        def.extend_from_slice(b"def");
+        def.extend_from_slice(&[2, b'd', b'b', 1, b't', 1, b't', name.len() as u8]);
+        def.extend_from_slice(name.as_bytes());
+        def.push(name.len() as u8);
+        def.extend_from_slice(name.as_bytes());
+        def.extend_from_slice(&[0x0c, 0x21, 0, 0xff, 0xff, 0, 0, 0xfd, 0, 0, 0, 0, 0]);
+        mysql_packet(&mut out, seq_id, &def);
+    }
+    seq_id = seq_id.wrapping_add(1);
+    mysql_packet(&mut out, seq_id, &[0xfe, 0, 0, 0x22, 0]);
+    let column_len = (row_len / COLUMNS).clamp(1, 250);
+    let mut row = vec![];
+    for _ in 0..COLUMNS {
+        row.push(column_len as u8);
+        row.resize(row.len() + column_len, b'x');
+    }
+    for _ in 0..rows {
+        seq_id = seq_id.wrapping_add(1);
+        mysql_packet(&mut out, seq_id, &row);
+    }
+    seq_id = seq_id.wrapping_add(1);
+    mysql_packet(&mut out, seq_id, &[0xfe, 0, 0, 0x22, 0]);
+    out
+}
+
+fn arg(args: &[String], index: usize, default: usize) -> Result<usize, Box<dyn Error>> {
+    match args.get(index) {
+        Some(v) => Ok(v.parse()?),
+        None => Ok(default)
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().collect();
+    let path = args.get(1).ok_or("usage: resultset_pcap <file> [connections] [queries] [rows] [row_len]")?;
+    let connections = arg(&args, 2, 20)?;
+    let queries = arg(&args, 3, 50)?;
+    let rows = arg(&args, 4, 2000)?;
+    let row_len = arg(&args, 5, 200)?;
+
+    let mut writer = PcapWriter::new(path)?;
+    let response = result_set(rows, row_len);
+    for c in 0..connections {
+        let client = [10, 0, 1 + (c / 250) as u8, 2 + (c % 250) as u8];
+        let mut request_flow = Flow { source: client, destination: SERVER, source_port: 30000 + c as u16,
+            destination_port: SERVER_PORT, seq: 1000 };
+        let mut response_flow = Flow { source: SERVER, destination: client, source_port: SERVER_PORT,
+            destination_port: 30000 + c as u16, seq: 5000 };
+        for q in 0..queries {
+            let mut request = vec![];
+            mysql_packet(&mut request, 0, format!("\x03select c0, c1, c2, c3 from t where id > {}", q).as_bytes());
+            request_flow.send(&mut writer, &request, response_flow.seq)?;
+            response_flow.send(&mut writer, &response, request_flow.seq)?;
+        }
+    }
+    writer.out.flush()?;
+    println!("{}: {} packets, {} bytes of result set per query", path, writer.packets, response.len());
+    Ok(())
+}
@@ -0,0 +1,377 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/4/18
+*/
+use std::error::Error;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use crate::capture::{PacketSource, RawPacket};
+use crate::packet::UnixTime;
+use crate::packet::network::LinkType;
+
+const PACKET_RX_RING: c_int = 5;
+const PACKET_STATISTICS: c_int = 6;
+const PACKET_VERSION: c_int = 10;
+const PACKET_FANOUT: c_int = 18;
+const TPACKET_V3: c_int = 2;
+const PACKET_FANOUT_HASH: u32 = 0;
+const PACKET_FANOUT_FLAG_DEFRAG: u32 = 0x8000;
+
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+const TP_STATUS_VLAN_VALID: u32 = 0x10;
+const TP_STATUS_VLAN_TPID_VALID: u32 = 0x40;
+
+const ARPHRD_RAWIP: u16 = 519;                  // libc中没有定义, 部分4G/5G模块的网卡类型
+const DLT_EN10MB: c_int = 1;
+const DLT_RAW: c_int = 12;
+
+const BLOCK_SIZE: u32 = 1 << 22;                // 每个block 4MB
+const FRAME_SIZE: u32 = 1 << 11;                // TPACKET_V3中frame大小只用于计算frame数量
+const BLOCK_TIMEOUT_MS: u32 = 60;               // block未写满时交给用户态的超时时间
+const POLL_TIMEOUT_MS: c_int = 1000;
+
+// tpacket_block_desc中各字段的偏移
+const BLOCK_STATUS_OFFSET: usize = 8;
+const BLOCK_NUM_PKTS_OFFSET: usize = 12;
+const BLOCK_FIRST_PKT_OFFSET: usize = 16;
+
+///
+/// struct tpacket_req3
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: c_uint,
+    tp_block_nr: c_uint,
+    tp_frame_size: c_uint,
+    tp_frame_nr: c_uint,
+    tp_retire_blk_tov: c_uint,
+    tp_sizeof_priv: c_uint,
+    tp_feature_req_word: c_uint,
+}
+
+///
+/// struct tpacket3_hdr, 只包含用到的字段
+#[repr(C)]
+struct Tpacket3Hdr {
+    tp_next_offset: u32,
+    tp_sec: u32,
+    tp_nsec: u32,
+    tp_snaplen: u32,
+    tp_len: u32,
+    tp_status: u32,
+    tp_mac: u16,
+    tp_net: u16,
+    tp_rxhash: u32,
+    tp_vlan_tci: u32,
+    tp_vlan_tpid: u16,
+}
+
+///
+/// struct tpacket_stats_v3
+#[repr(C)]
+#[derive(Default)]
+struct TpacketStatsV3 {
+    tp_packets: c_uint,
+    tp_drops: c_uint,
+    tp_freeze_q_cnt: c_uint,
+}
+
+///
+/// struct bpf_program, 与内核的struct sock_fprog布局相同
+#[repr(C)]
+struct BpfProgram {
+    bf_len: c_uint,
+    bf_insns: *mut c_void,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *mut c_void,
+}
+
+#[link(name = "pcap")]
+extern "C" {
+    fn pcap_open_dead(linktype: c_int, snaplen: c_int) -> *mut c_void;
+    fn pcap_compile(p: *mut c_void, fp: *mut BpfProgram, s: *const c_char, optimize: c_int, netmask: c_uint) -> c_int;
+    fn pcap_freecode(fp: *mut BpfProgram);
+    fn pcap_close(p: *mut c_void);
+}
+
+///
+/// 根据网卡的硬件类型(sll_hatype, ARPHRD_*)确定帧的格式, 与libpcap的对应关系一致
+/// loopback网卡的帧带有全0的以太网头部, tun及各类隧道网卡的帧直接从ip头部开始
+fn link_type_from_hatype(hatype: u16) -> Option<LinkType> {
+    match hatype {
+        libc::ARPHRD_ETHER | libc::ARPHRD_LOOPBACK => Some(LinkType::Ethernet),
+        libc::ARPHRD_NONE | libc::ARPHRD_TUNNEL | libc::ARPHRD_TUNNEL6 | libc::ARPHRD_SIT
+        | libc::ARPHRD_IPGRE | ARPHRD_RAWIP => Some(LinkType::Raw),
+        _ => None
+    }
+}
+
+///
+/// AF_PACKET抓包后端, 使用TPACKET_V3的mmap环形缓冲区, 内核按block批量交给用户态, 读取时不复制
+/// 开启fanout时同一个组中的多个socket按连接hash分担流量
+pub struct AfPacketSource {
+    fd: c_int,
+    link_type: LinkType,                        // 根据网卡的硬件类型确定
+    ring: *mut u8,
+    block_nr: usize,
+    block: usize,                               // 当前读取的block
+    remaining: u32,                             // 当前block中还未读取的包数量, 为0时还未开始读取
+    next_offset: usize,                         // 下一个包在当前block中的偏移
+    scratch: Vec<u8>,                           // 内核剥离vlan tag后重新插入tag的缓冲区
+    dropped: u64,                               // 内核因ring满丢弃的包数
+}
+
+// ring只由持有该结构的线程访问
+unsafe impl Send for AfPacketSource {}
+
+impl AfPacketSource {
+    ///
+    /// 打开网卡, filter按链路层类型生成过滤表达式, ring_blocks为4MB block的数量, fanout为fanout组id
+    /// 先绑定网卡并获取网卡类型, 再按对应的链路层类型编译过滤条件及创建ring
+    pub fn new(ifname: &str, filter: &dyn Fn(LinkType) -> String, ring_blocks: u32, fanout: Option<u16>) -> Result<AfPacketSource, Box<dyn Error>> {
+        let protocol = (libc::ETH_P_ALL as u16).to_be() as c_int;
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // fd先交给结构体管理, 后续失败时在drop中关闭
+        let mut source = AfPacketSource {
+            fd,
+            link_type: LinkType::Ethernet,
+            ring: ptr::null_mut(),
+            block_nr: ring_blocks as usize,
+            block: 0,
+            remaining: 0,
+            next_offset: 0,
+            scratch: vec![],
+            dropped: 0,
+        };
+
+        let name = CString::new(ifname)?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(format!("device not found: {}", ifname).into());
+        }
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = protocol as u16;
+        addr.sll_ifindex = ifindex as c_int;
+        let ret = unsafe {
+            libc::bind(fd, &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                       mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // 绑定后内核在地址中填入网卡的硬件类型
+        let mut len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockname(fd, &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr, &mut len)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        source.link_type = match link_type_from_hatype(addr.sll_hatype) {
+            Some(v) => v,
+            None => return Err(format!("unsupported hardware type {} of {}", addr.sll_hatype, ifname).into())
+        };
+
+        source.set_option(PACKET_VERSION, &TPACKET_V3)?;
+        source.attach_filter(&filter(source.link_type))?;
+        let req = TpacketReq3 {
+            tp_block_size: BLOCK_SIZE,
+            tp_block_nr: ring_blocks,
+            tp_frame_size: FRAME_SIZE,
+            tp_frame_nr: BLOCK_SIZE / FRAME_SIZE * ring_blocks,
+            tp_retire_blk_tov: BLOCK_TIMEOUT_MS,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        source.set_option(PACKET_RX_RING, &req)?;
+        let ring = unsafe {
+            libc::mmap(ptr::null_mut(), BLOCK_SIZE as usize * source.block_nr,
+                       libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0)
+        };
+        if ring == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        source.ring = ring as *mut u8;
+        if let Some(group) = fanout {
+            let value = group as u32 | ((PACKET_FANOUT_HASH | PACKET_FANOUT_FLAG_DEFRAG) << 16);
+            source.set_option(PACKET_FANOUT, &value)?;
+        }
+        Ok(source)
+    }
+
+    fn set_option<T>(&self, name: c_int, value: &T) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(self.fd, libc::SOL_PACKET, name, value as *const T as *const c_void,
+                             mem::size_of::<T>() as libc::socklen_t)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    ///
+    /// 使用libpcap按网卡的链路层类型将过滤表达式编译为BPF字节码并加载到socket
+    fn attach_filter(&self, filter: &str) -> Result<(), Box<dyn Error>> {
+        let expr = CString::new(filter)?;
+        let mut program = BpfProgram { bf_len: 0, bf_insns: ptr::null_mut() };
+        let dlt = match self.link_type {
+            LinkType::Raw => DLT_RAW,
+            _ => DLT_EN10MB
+        };
+        unsafe {
+            let pcap = pcap_open_dead(dlt, 65535);
+            if pcap.is_null() {
+                return Err("pcap_open_dead failed".into());
+            }
+            let ret = pcap_compile(pcap, &mut program, expr.as_ptr(), 1, 0xffff_ffff);
+            pcap_close(pcap);
+            if ret != 0 {
+                return Err(format!("invalid filter: {}", filter).into());
+            }
+            let fprog = SockFprog { len: program.bf_len as u16, filter: program.bf_insns };
+            let ret = libc::setsockopt(self.fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER,
+                                       &fprog as *const SockFprog as *const c_void,
+                                       mem::size_of::<SockFprog>() as libc::socklen_t);
+            pcap_freecode(&mut program);
+            if ret != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+
+    fn block_ptr(&self, block: usize) -> *mut u8 {
+        unsafe { self.ring.add(block * BLOCK_SIZE as usize) }
+    }
+
+    fn block_status(&self, block: usize) -> u32 {
+        let status = unsafe { ptr::read_volatile(self.block_ptr(block).add(BLOCK_STATUS_OFFSET) as *const u32) };
+        fence(Ordering::Acquire);
+        status
+    }
+
+    ///
+    /// 当前block读取完毕, 交还给内核
+    fn release_block(&mut self) {
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(self.block_ptr(self.block).add(BLOCK_STATUS_OFFSET) as *mut u32, TP_STATUS_KERNEL) };
+        self.block = (self.block + 1) % self.block_nr;
+        self.remaining = 0;
+    }
+
+    ///
+    /// 等待当前block可读
+    fn wait_block(&self) -> io::Result<bool> {
+        if self.block_status(self.block) & TP_STATUS_USER != 0 {
+            return Ok(true);
+        }
+        let mut pfd = libc::pollfd { fd: self.fd, events: libc::POLLIN | libc::POLLERR, revents: 0 };
+        let ret = unsafe { libc::poll(&mut pfd, 1, POLL_TIMEOUT_MS) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        Ok(self.block_status(self.block) & TP_STATUS_USER != 0)
+    }
+
+    ///
+    /// 读取内核的计数, 读取后内核清零
+    fn update_stats(&mut self) {
+        let mut stats = TpacketStatsV3::default();
+        let mut len = mem::size_of::<TpacketStatsV3>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(self.fd, libc::SOL_PACKET, PACKET_STATISTICS,
+                             &mut stats as *mut TpacketStatsV3 as *mut c_void, &mut len)
+        };
+        if ret == 0 {
+            self.dropped += stats.tp_drops as u64;
+        }
+    }
+}
+
+impl PacketSource for AfPacketSource {
+    fn next_packet(&mut self) -> Result<Option<RawPacket<'_>>, Box<dyn Error>> {
+        if self.remaining == 0 && self.next_offset != 0 {
+            // 上一个block中的包已全部读取, 此时不再有借用
+            self.release_block();
+            self.next_offset = 0;
+        }
+        while self.remaining == 0 {
+            if !self.wait_block()? {
+                continue;
+            }
+            let block = self.block_ptr(self.block);
+            let num_pkts = unsafe { ptr::read(block.add(BLOCK_NUM_PKTS_OFFSET) as *const u32) };
+            if num_pkts == 0 {
+                self.release_block();
+                continue;
+            }
+            self.remaining = num_pkts;
+            self.next_offset = unsafe { ptr::read(block.add(BLOCK_FIRST_PKT_OFFSET) as *const u32) } as usize;
+            self.update_stats();
+        }
+
+        let block = self.block_ptr(self.block);
+        let hdr = unsafe { &*(block.add(self.next_offset) as *const Tpacket3Hdr) };
+        let frame = unsafe {
+            std::slice::from_raw_parts(block.add(self.next_offset + hdr.tp_mac as usize), hdr.tp_snaplen as usize)
+        };
+        let ts = UnixTime { tv_sec: hdr.tp_sec as u64, tv_usec: (hdr.tp_nsec / 1000) as u64 };
+        let mut len = hdr.tp_len;
+        let status = hdr.tp_status;
+        let (vlan_tci, vlan_tpid) = (hdr.tp_vlan_tci as u16, hdr.tp_vlan_tpid);
+        self.remaining -= 1;
+        self.next_offset = if self.remaining == 0 { 1 } else { self.next_offset + hdr.tp_next_offset as usize };
+
+        let data = if self.link_type == LinkType::Ethernet && status & TP_STATUS_VLAN_VALID != 0 && frame.len() >= 12 {
+            // 网卡剥离了vlan tag, 重新插入到以太网头部之后, 与libpcap的行为一致
+            let tpid = if status & TP_STATUS_VLAN_TPID_VALID != 0 { vlan_tpid } else { 0x8100 };
+            self.scratch.clear();
+            self.scratch.extend_from_slice(&frame[..12]);
+            self.scratch.extend_from_slice(&tpid.to_be_bytes());
+            self.scratch.extend_from_slice(&vlan_tci.to_be_bytes());
+            self.scratch.extend_from_slice(&frame[12..]);
+            len += 4;
+            &self.scratch[..]
+        } else {
+            frame
+        };
+        Ok(Some(RawPacket { ts, len, data }))
+    }
+
+    fn link_type(&self) -> LinkType {
+        self.link_type
+    }
+
+    fn dropped(&mut self) -> u64 {
+        self.update_stats();
+        self.dropped
+    }
+}
+
+impl Drop for AfPacketSource {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.ring.is_null() {
+                libc::munmap(self.ring as *mut c_void, BLOCK_SIZE as usize * self.block_nr);
+            }
+            libc::close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/4/18
+*/
+use std::error::Error;
+use std::ffi::CStr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use pcap::{Activated, Capture};
+use crate::packet::UnixTime;
+use crate::packet::network::LinkType;
+
+///
+/// 抓包后端读取到的原始数据包, data借用后端的缓冲区, 在读取下一个包之前有效
+pub struct RawPacket<'a> {
+    pub ts: UnixTime,
+    pub len: u32,                   // 包的原始长度
+    pub data: &'a [u8],             // 抓取到的数据, 长度可能小于len
+}
+
+///
+/// 抓包后端, 解析部分只依赖该接口, 不关心数据来自libpcap还是AF_PACKET
+pub trait PacketSource {
+    ///
+    /// 读取下一个包, 离线文件读取完毕时返回None
+    fn next_packet(&mut self) -> Result<Option<RawPacket<'_>>, Box<dyn Error>>;
+
+    fn link_type(&self) -> LinkType;
+
+    ///
+    /// 内核或网卡因缓冲区满丢弃的包数
+    fn dropped(&mut self) -> u64;
+}
+
+///
+/// libpcap后端, 用于监听网卡及读取离线文件
+pub struct PcapSource<T: Activated + ?Sized> {
+    cap: Capture<T>,
+    link_type: LinkType,
+}
+
+impl<T: Activated + ?Sized> PcapSource<T> {
+    pub fn new(cap: Capture<T>) -> Result<PcapSource<T>, Box<dyn Error>> {
+        let link_type = match LinkType::from_dlt(cap.get_datalink().0) {
+            Some(v) => v,
+            None => return Err(format!("unsupported datalink type: {:?}", cap.get_datalink()).into())
+        };
+        Ok(PcapSource { cap, link_type })
+    }
+}
+
+impl<T: Activated + ?Sized> PacketSource for PcapSource<T> {
+    fn next_packet(&mut self) -> Result<Option<RawPacket<'_>>, Box<dyn Error>> {
+        match self.cap.next() {
+            Ok(packet) => Ok(Some(RawPacket {
+                ts: UnixTime::new(&packet.header.ts)?,
+                len: packet.header.len,
+                data: packet.data,
+            })),
+            Err(pcap::Error::NoMorePackets) => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    fn link_type(&self) -> LinkType {
+        self.link_type
+    }
+
+    fn dropped(&mut self) -> u64 {
+        // 离线文件不支持统计
+        self.cap.stats().map(|s| s.dropped as u64).unwrap_or(0)
+    }
+}
+
+///
+/// 读取网卡上配置的ipv4/ipv6地址
+pub fn interface_addresses(name: &str) -> io::Result<Vec<IpAddr>> {
+    let mut addrs = vec![];
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut cur = ifap;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() || unsafe { CStr::from_ptr(ifa.ifa_name) }.to_bytes() != name.as_bytes() {
+            continue;
+        }
+        let addr = match unsafe { (*ifa.ifa_addr).sa_family } as i32 {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+            }
+            _ => continue
+        };
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+    Ok(addrs)
+}
@@ -0,0 +1,94 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/4/20
+*/
+use std::collections::{HashMap, VecDeque};
+use crate::flow::FlowKey;
+use crate::packet::StreamPacket;
+
+///
+/// 判断重复帧的键, 同一方向的四元组、tcp序列号、确认号、ip id、数据长度及tcp标记都相同的帧视为同一个帧
+/// 纯ack包的序列号不变, 需要加上确认号区分
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FrameKey {
+    flow: FlowKey,
+    seq: u32,
+    ack: u32,
+    ip_id: Option<u16>,
+    payload_len: usize,
+    packet_flag: u8,
+}
+
+///
+/// 重复帧计数
+#[derive(Debug, Default, Clone)]
+pub struct DuplicateStats {
+    pub dropped: u64,                           // 丢弃的重复帧
+}
+
+///
+/// 镜像端口同时镜像出入方向或多个交换机镜像同一条流时, 同一个帧会收到多次
+/// 在时间窗口内见到完全相同的帧时丢弃, 重传的包ip id不同或超出窗口, 不受影响
+/// 只记录窗口内的帧, 内存占用与窗口内的包数量成正比
+pub struct DuplicateFilter {
+    window: u64,                                // 时间窗口, 微秒, 为0时不检查
+    seen: HashMap<FrameKey, u64>,               // 帧 -> 最近一次见到的时间
+    order: VecDeque<(u64, FrameKey)>,           // 按见到的时间排列, 用于淘汰窗口外的帧
+    pub stats: DuplicateStats,
+}
+
+impl DuplicateFilter {
+    pub fn new(window_ms: u64) -> DuplicateFilter {
+        DuplicateFilter {
+            window: window_ms * 1000,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            stats: DuplicateStats::default(),
+        }
+    }
+
+    ///
+    /// 返回true表示窗口内已见到相同的帧, 该包需要丢弃
+    pub fn is_duplicate(&mut self, packet: &StreamPacket) -> bool {
+        if self.window == 0 {
+            return false;
+        }
+        let now = packet.ts.tv_sec * 1_000_000 + packet.ts.tv_usec;
+        self.expire(now);
+        let key = FrameKey {
+            flow: packet.stream_key(false),
+            seq: packet.seq,
+            ack: packet.ack,
+            ip_id: packet.ip_id,
+            payload_len: packet.payload.len(),
+            packet_flag: packet.packet_flag,
+        };
+        if let Some(last) = self.seen.get(&key) {
+            if now < last + self.window {
+                self.stats.dropped += 1;
+                return true;
+            }
+        }
+        self.seen.insert(key, now);
+        self.order.push_back((now, key));
+        false
+    }
+
+    ///
+    /// 淘汰窗口外的帧, 时间回退时(多个网卡的包交错)保留到窗口结束
+    fn expire(&mut self, now: u64) {
+        while let Some((ts, key)) = self.order.front() {
+            if ts + self.window > now {
+                break;
+            }
+            if self.seen.get(key) == Some(ts) {
+                self.seen.remove(key);
+            }
+            self.order.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+}
@@ -0,0 +1,294 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/4/19
+*/
+use std::collections::HashSet;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use crate::Config;
+use crate::lru::LruIndex;
+use crate::packet::StreamPacket;
+use crate::packet::network::{TCP_SYN, TCP_ACK};
+
+const MYSQL_PORTS: [u16; 3] = [3306, 33060, 33062];  // mysql常用的监听端口
+const HANDSHAKE_V10: u8 = 0x0a;
+
+///
+/// 服务端的监听地址, 带有vlan tag时加上vlan id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    pub addr: SocketAddr,
+    pub vlan_id: Option<u16>,
+    pub outer_vlan_id: Option<u16>,
+}
+
+///
+/// 判断连接中哪一端是mysql服务端, 不需要指定本机所属方向
+/// 依次根据syn/syn-ack、服务端发送的握手包(0x0a)、已学习到的监听地址及端口判断
+/// 通过syn及握手包确定的服务端地址会记录下来, 用于判断之后没有见到连接建立过程的数据流
+pub struct ServerTable {
+    servers: HashSet<Endpoint>,
+    lru: LruIndex<Endpoint>,
+    max_entries: usize,
+}
+
+impl ServerTable {
+    pub fn new(max_entries: usize) -> ServerTable {
+        ServerTable { servers: HashSet::new(), lru: LruIndex::new(), max_entries }
+    }
+
+    ///
+    /// 返回数据包中服务端一侧是否为目标地址
+    pub fn destination_is_server(&mut self, packet: &StreamPacket, conf: &Config) -> bool {
+        let source = self.endpoint(packet, packet.source, packet.source_port);
+        let destination = self.endpoint(packet, packet.destination, packet.destination_port);
+        let now = packet.ts.tv_sec;
+        if packet.packet_flag & TCP_SYN != 0 {
+            // syn由客户端发出, syn-ack由服务端发出
+            let server = if packet.packet_flag & TCP_ACK != 0 { source } else { destination };
+            self.learn(&server, now);
+            return server == destination;
+        }
+        if is_handshake(&packet.payload) {
+            self.learn(&source, now);
+            return false;
+        }
+        match (self.servers.contains(&source), self.servers.contains(&destination)) {
+            (true, false) => {
+                self.lru.touch(&source, now);
+                return false;
+            }
+            (false, true) => {
+                self.lru.touch(&destination, now);
+                return true;
+            }
+            _ => {}
+        }
+        destination_port_is_server(packet.source_port, packet.destination_port, conf)
+    }
+
+    fn endpoint(&self, packet: &StreamPacket, ip: IpAddr, port: u16) -> Endpoint {
+        Endpoint { addr: SocketAddr::new(ip, port), vlan_id: packet.vlan_id, outer_vlan_id: packet.outer_vlan_id }
+    }
+
+    fn learn(&mut self, server: &Endpoint, now: u64) {
+        if self.servers.insert(*server) {
+            while self.servers.len() > self.max_entries {
+                match self.lru.pop_oldest() {
+                    Some(v) => { self.servers.remove(&v); }
+                    None => break
+                }
+            }
+        }
+        self.lru.touch(server, now);
+    }
+
+    ///
+    /// 删除超过timeout秒未见到流量的监听地址
+    pub fn expire(&mut self, now: u64, timeout: u64) {
+        for server in self.lru.pop_idle(now, timeout) {
+            self.servers.remove(&server);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+}
+
+///
+/// 服务端连接建立后发送的第一个包为HandshakeV10: seq_id为0, 协议版本0x0a, 之后为以0结尾的版本号字符串
+/// 客户端发送的COM_PROCESS_INFO(0x0a)只有一个字节, 通过长度及版本号字符串区分
+fn is_handshake(payload: &[u8]) -> bool {
+    if payload.len() < 6 || payload[3] != 0 || payload[4] != HANDSHAKE_V10 {
+        return false;
+    }
+    let len = payload[0] as usize | (payload[1] as usize) << 8 | (payload[2] as usize) << 16;
+    len > 1 && payload[5..].contains(&0)
+}
+
+///
+/// 没有其他依据时按端口判断: 指定的监听端口、mysql常用端口, 最后取端口号较小的一端
+fn destination_port_is_server(source_port: u16, destination_port: u16, conf: &Config) -> bool {
+    for ports in &[&conf.ports[..], &MYSQL_PORTS[..]] {
+        match (ports.contains(&source_port), ports.contains(&destination_port)) {
+            (true, false) => return false,
+            (false, true) => return true,
+            _ => {}
+        }
+    }
+    destination_port <= source_port
+}
+
+///
+/// 镜像模式下需要审计的服务端, 可以是ip:端口、ip或网段
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerMatch {
+    pub addr: IpAddr,
+    pub prefix: u8,                             // 网段的前缀长度, 单个地址为32/128
+    pub port: Option<u16>,
+}
+
+impl ServerMatch {
+    ///
+    /// 解析10.0.0.5:3306、[2001:db8::5]:3306、10.0.0.5、10.0.0.0/24
+    pub fn parse(value: &str) -> Result<ServerMatch, Box<dyn Error>> {
+        if let Some((addr, prefix)) = value.split_once('/') {
+            let addr: IpAddr = addr.parse()?;
+            let prefix: u8 = prefix.parse()?;
+            if prefix > max_prefix(&addr) {
+                return Err(format!("invalid prefix length: {}", value).into());
+            }
+            return Ok(ServerMatch { addr, prefix, port: None });
+        }
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            return Ok(ServerMatch { addr: addr.ip(), prefix: max_prefix(&addr.ip()), port: Some(addr.port()) });
+        }
+        let addr: IpAddr = value.trim_start_matches('[').trim_end_matches(']').parse()?;
+        Ok(ServerMatch { addr, prefix: max_prefix(&addr), port: None })
+    }
+
+    pub fn contains(&self, addr: &IpAddr, port: u16) -> bool {
+        if let Some(p) = self.port {
+            if p != port {
+                return false;
+            }
+        }
+        match (self.network(), addr) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => u32::from(net) == u32::from(*ip) & v4_mask(self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => u128::from(net) == u128::from(*ip) & v6_mask(self.prefix),
+            _ => false
+        }
+    }
+
+    ///
+    /// 去掉主机位后的网段地址, 10.0.0.5/24为10.0.0.0
+    fn network(&self) -> IpAddr {
+        match self.addr {
+            IpAddr::V4(net) => IpAddr::V4((u32::from(net) & v4_mask(self.prefix)).into()),
+            IpAddr::V6(net) => IpAddr::V6((u128::from(net) & v6_mask(self.prefix)).into()),
+        }
+    }
+
+    ///
+    /// 对应的BPF过滤表达式, 网段带有主机位时libpcap会报错, 使用去掉主机位后的地址
+    pub fn bpf_filter(&self) -> String {
+        let host = if self.prefix == max_prefix(&self.addr) {
+            format!("host {}", self.addr)
+        } else {
+            format!("net {}/{}", self.network(), self.prefix)
+        };
+        match self.port {
+            Some(port) => format!("({} and port {})", host, port),
+            None => host
+        }
+    }
+}
+
+fn max_prefix(addr: &IpAddr) -> u8 {
+    if addr.is_ipv4() { 32 } else { 128 }
+}
+
+fn v4_mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn v6_mask(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+///
+/// 镜像模式下判断地址是否为需要审计的服务端
+pub fn is_mirror_server(conf: &Config, addr: &IpAddr, port: u16) -> bool {
+    conf.mirror.iter().any(|m| m.contains(addr, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_server_match() {
+        assert_eq!(ServerMatch::parse("10.0.0.5:3306").unwrap(),
+                   ServerMatch { addr: ip("10.0.0.5"), prefix: 32, port: Some(3306) });
+        assert_eq!(ServerMatch::parse("[2001:db8::5]:3306").unwrap(),
+                   ServerMatch { addr: ip("2001:db8::5"), prefix: 128, port: Some(3306) });
+        assert_eq!(ServerMatch::parse("10.0.0.5").unwrap(),
+                   ServerMatch { addr: ip("10.0.0.5"), prefix: 32, port: None });
+        assert_eq!(ServerMatch::parse("2001:db8::5").unwrap(),
+                   ServerMatch { addr: ip("2001:db8::5"), prefix: 128, port: None });
+        assert_eq!(ServerMatch::parse("10.0.0.0/24").unwrap(),
+                   ServerMatch { addr: ip("10.0.0.0"), prefix: 24, port: None });
+        assert_eq!(ServerMatch::parse("2001:db8::/32").unwrap(),
+                   ServerMatch { addr: ip("2001:db8::"), prefix: 32, port: None });
+        assert!(ServerMatch::parse("10.0.0.0/33").is_err());
+        assert!(ServerMatch::parse("2001:db8::/129").is_err());
+        assert!(ServerMatch::parse("10.0.0.0/x").is_err());
+        assert!(ServerMatch::parse("10.0.0.x").is_err());
+        assert!(ServerMatch::parse("10.0.0.5:99999").is_err());
+    }
+
+    #[test]
+    fn server_match_contains_v4() {
+        let server = ServerMatch::parse("10.0.0.5:3306").unwrap();
+        assert!(server.contains(&ip("10.0.0.5"), 3306));
+        assert!(!server.contains(&ip("10.0.0.5"), 3307));
+        assert!(!server.contains(&ip("10.0.0.6"), 3306));
+        let net = ServerMatch::parse("10.0.1.5/23").unwrap();
+        assert!(net.contains(&ip("10.0.0.1"), 3306));
+        assert!(net.contains(&ip("10.0.1.255"), 33060));
+        assert!(!net.contains(&ip("10.0.2.1"), 3306));
+        assert!(!net.contains(&ip("::ffff:10.0.0.1"), 3306));
+        let all = ServerMatch::parse("0.0.0.0/0").unwrap();
+        assert!(all.contains(&ip("192.168.1.1"), 3306));
+        assert!(!all.contains(&ip("2001:db8::1"), 3306));
+    }
+
+    #[test]
+    fn server_match_contains_v6() {
+        let net = ServerMatch::parse("2001:db8:0:1::/64").unwrap();
+        assert!(net.contains(&ip("2001:db8:0:1::5"), 3306));
+        assert!(net.contains(&ip("2001:db8:0:1:ffff:ffff:ffff:ffff"), 3306));
+        assert!(!net.contains(&ip("2001:db8:0:2::5"), 3306));
+        assert!(!net.contains(&ip("10.0.0.1"), 3306));
+        let server = ServerMatch::parse("[2001:db8::5]:3306").unwrap();
+        assert!(server.contains(&ip("2001:db8::5"), 3306));
+        assert!(!server.contains(&ip("2001:db8::6"), 3306));
+        let all = ServerMatch::parse("::/0").unwrap();
+        assert!(all.contains(&ip("2001:db8::1"), 3306));
+        assert!(!all.contains(&ip("10.0.0.1"), 3306));
+    }
+
+    #[test]
+    fn server_match_bpf_filter() {
+        assert_eq!(ServerMatch::parse("10.0.0.5:3306").unwrap().bpf_filter(), "(host 10.0.0.5 and port 3306)");
+        assert_eq!(ServerMatch::parse("2001:db8::5").unwrap().bpf_filter(), "host 2001:db8::5");
+        assert_eq!(ServerMatch::parse("10.0.0.0/24").unwrap().bpf_filter(), "net 10.0.0.0/24");
+        assert_eq!(ServerMatch::parse("10.0.0.5/24").unwrap().bpf_filter(), "net 10.0.0.0/24");
+        assert_eq!(ServerMatch::parse("2001:db8::5/32").unwrap().bpf_filter(), "net 2001:db8::/32");
+    }
+
+    #[test]
+    fn handshake_payload() {
+        let mut payload = vec![0, 0, 0, 0, HANDSHAKE_V10];
+        payload.extend_from_slice(b"8.0.21\0");
+        payload.extend_from_slice(&[7, 0, 0, 0]);
+        let len = payload.len() as u32 - 4;
+        payload[..3].copy_from_slice(&len.to_le_bytes()[..3]);
+        assert!(is_handshake(&payload));
+        // 10列结果集的列数包
+        assert!(!is_handshake(&[0x01, 0, 0, 0x01, 0x0a]));
+        let mut payload = vec![0x01, 0, 0, 0x01, 0x0a, 0x03];
+        payload.extend_from_slice(b"def\0");
+        assert!(!is_handshake(&payload));
+        // 客户端发送的COM_PROCESS_INFO
+        assert!(!is_handshake(&[0x01, 0, 0, 0, 0x0a]));
+        assert!(!is_handshake(&[0x01, 0, 0, 0, 0x0a, 0]));
+        // 版本号没有结尾的0
+        assert!(!is_handshake(&[0x05, 0, 0, 0, 0x0a, b'8', b'.', b'0', b'.']));
+    }
+}
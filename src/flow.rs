@@ -0,0 +1,29 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/4/12
+*/
+use std::net::SocketAddr;
+
+///
+/// tcp流的四元组及vlan id, 用作session表、连接表及tcp重组的键
+/// session及连接使用客户端->服务端方向, tcp重组使用数据包本身的方向
+/// 带有vlan tag时加上vlan id, 避免不同环境中相同的ip地址冲突
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FlowKey {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub vlan_id: Option<u16>,
+    pub outer_vlan_id: Option<u16>,
+}
+
+impl FlowKey {
+    pub fn new(source: SocketAddr, destination: SocketAddr, vlan_id: Option<u16>, outer_vlan_id: Option<u16>) -> FlowKey {
+        FlowKey { source, destination, vlan_id, outer_vlan_id }
+    }
+
+    ///
+    /// 相反方向的键
+    pub fn reverse(&self) -> FlowKey {
+        FlowKey { source: self.destination, destination: self.source, ..*self }
+    }
+}
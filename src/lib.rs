@@ -5,10 +5,26 @@
 
 mod packet;
 mod session;
-use pcap::{Device, Capture};
+mod reassembly;
+mod lru;
+mod flow;
+mod pipeline;
+mod runtime;
+mod capture;
+mod direction;
+mod dedup;
+#[cfg(target_os = "linux")]
+mod afpacket;
+use pcap::{Device, Capture, Active, Activated};
 use structopt::StructOpt;
 use std::io::{Seek, SeekFrom, Result};
 use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use packet::network::{LinkType, TCP_SYN, TCP_FIN, TCP_RST, TCP_ACK};
+use capture::{PacketSource, PcapSource, RawPacket};
 
 
 pub trait Tell: Seek {
@@ -22,112 +38,483 @@ impl<T> Tell for T where T: Seek { }
 #[derive(Debug, StructOpt)]
 #[structopt(name = "example", about = "An example of StructOpt usage.")]
 pub struct Opt {
-    #[structopt(long = "host", short= "h", help="本机地址, 默认127.0.0.1")]
+    #[structopt(long = "host", short= "h", help="本机地址, 支持ipv4/ipv6, 多个地址用逗号分隔, 默认读取监听网卡上配置的地址")]
     pub host: Option<String>,
 
-    #[structopt(long = "dtype", short= "t", help="本机所属方向(发送方/接受方), 可佩src/des, 默认des")]
+    #[structopt(long = "dtype", short= "t", help="本机所属方向(发送方/接受方), 可佩src/des, 默认根据syn、握手包及端口自动判断服务端")]
     pub dtype: Option<String>,
 
-    #[structopt(long = "port", short= "p", help="监听那个端口的数据流，默认所有")]
+    #[structopt(long = "port", short= "p", help="监听那个端口的数据流, 多个端口用逗号分隔, 默认所有")]
     pub port: Option<String>,
 
-    #[structopt(long = "ethernet", short= "e", help="监听的网卡，默认eth0")]
+    #[structopt(long = "ethernet", short= "e", help="监听的网卡, 多个网卡用逗号分隔, 同时抓包并合并到同一个session表, 默认eth0")]
     pub ethernet: Option<String>,
 
+    #[structopt(long = "read-file", short= "r", help="读取离线的pcap/pcapng文件进行解析, 指定后不再监听网卡")]
+    pub read_file: Option<String>,
+
+    #[structopt(long = "filter", short= "f", help="附加的BPF过滤表达式, 与根据host/port生成的过滤条件取交集")]
+    pub filter: Option<String>,
+
+    #[structopt(long = "max-sql-len", help="sql文本最多保留的字节数, 超出部分截断, 默认1048576")]
+    pub max_sql_len: Option<usize>,
+
+    #[structopt(long = "max-sessions", help="session表及连接表最多保存的条目数, 超出时淘汰最久未访问的条目, 默认100000")]
+    pub max_sessions: Option<usize>,
+
+    #[structopt(long = "idle-timeout", help="请求等待返回的超时秒数, 超时后作为未完成的记录输出, 默认300")]
+    pub idle_timeout: Option<u64>,
+
+    #[structopt(long = "conn-idle-timeout", help="连接空闲的超时秒数, 超时后删除连接及重组状态, 默认28800")]
+    pub conn_idle_timeout: Option<u64>,
+
+    #[structopt(long = "stats-interval", help="输出计数及表大小信息的间隔秒数, 0为只在结束时输出, 默认60")]
+    pub stats_interval: Option<u64>,
+
+    #[structopt(long = "threads", help="解析线程数, 大于1时按连接分发到多个线程处理, 默认1")]
+    pub threads: Option<usize>,
+
+    #[structopt(long = "async", help="使用异步方式监听网卡, 输出及监控接口运行在同一个事件循环中, 不支持离线文件")]
+    pub async_capture: bool,
+
+    #[structopt(long = "sink", help="异步模式下审计记录的输出位置, 可选stdout、file:路径、tcp:地址:端口, 默认stdout")]
+    pub sink: Option<String>,
+
+    #[structopt(long = "metrics-addr", help="异步模式下提供计数信息的http地址, 如127.0.0.1:9100, 默认不开启")]
+    pub metrics_addr: Option<String>,
+
+    #[structopt(long = "mirror", help="镜像模式, 审计镜像端口上的所有服务端, 服务端用ip:端口、ip或网段表示, 多个用逗号分隔, 指定后不再按本机地址过滤")]
+    pub mirror: Option<String>,
+
+    #[structopt(long = "dedup-window", help="丢弃该时间窗口(毫秒)内重复收到的相同帧, 用于镜像端口重复镜像的流量, 0为不检查, 默认50")]
+    pub dedup_window: Option<u64>,
+
+    #[structopt(long = "backend", help="监听网卡使用的抓包后端, 可选pcap、afpacket(TPACKET_V3), 默认pcap")]
+    pub backend: Option<String>,
+
+    #[structopt(long = "ring-blocks", help="afpacket后端环形缓冲区4MB block的数量, 默认32")]
+    pub ring_blocks: Option<u32>,
+
+    #[structopt(long = "fanout", help="afpacket后端加入的fanout组id, 同组的多个进程按连接分担流量, 默认不开启")]
+    pub fanout: Option<u16>,
+
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
-    pub host: String,
-    pub dtype: String,
-    pub ethernet: String,
-    pub port: u16,
+    pub hosts: Vec<IpAddr>,
+    pub dtype: Option<String>,
+    pub mirror: Vec<direction::ServerMatch>,
+    pub dedup_window: u64,
+    pub ethernet: Vec<String>,
+    pub ports: Vec<u16>,
+    pub read_file: Option<String>,
+    pub filter: Option<String>,
+    pub max_sql_len: usize,
+    pub max_sessions: usize,
+    pub idle_timeout: u64,
+    pub conn_idle_timeout: u64,
+    pub stats_interval: u64,
+    pub threads: usize,
+    pub async_capture: bool,
+    pub sink: Option<runtime::Sink>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub backend: Backend,
+    pub ring_blocks: u32,
+    pub fanout: Option<u16>,
+}
+
+///
+/// 监听网卡使用的抓包后端
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    Pcap,
+    AfPacket,
+}
+
+impl Backend {
+    pub fn parse(value: &str) -> std::result::Result<Backend, Box<dyn Error>> {
+        match value {
+            "pcap" => Ok(Backend::Pcap),
+            "afpacket" => Ok(Backend::AfPacket),
+            _ => Err(format!("unsupported backend: {}", value).into())
+        }
+    }
 }
 
 impl Config{
-    pub fn new(args: Opt) -> Config {
-        let mut host = String::from("127.0.0.1");
-        let mut dtype = String::from("des");
-        let mut ethernet = String::from("eth0");
-        let mut port : u16 = 0;
+    pub fn new(args: Opt) -> std::result::Result<Config, Box<dyn Error>> {
+        let mut hosts: Vec<IpAddr> = vec![];
+        let mut mirror = vec![];
+        let mut ethernet = vec![String::from("eth0")];
+        let mut ports: Vec<u16> = vec![];
 
         match args.host {
-            Some(t) => host = t,
+            Some(t) => {
+                for h in t.split(',').filter(|h| !h.trim().is_empty()) {
+                    hosts.push(h.trim().trim_start_matches('[').trim_end_matches(']').parse()
+                        .map_err(|e| format!("invalid host {}: {}", h.trim(), e))?);
+                }
+            }
             _ => {}
         }
 
-        match args.dtype {
-            Some(t) => dtype = t,
-            _ => {}
+        if let Some(t) = args.mirror {
+            for m in t.split(',').filter(|m| !m.trim().is_empty()) {
+                mirror.push(direction::ServerMatch::parse(m.trim())?);
+            }
         }
 
         match args.port {
-            Some(t) => {port = t.parse().unwrap()}
+            Some(t) => {
+                for p in t.split(',').filter(|p| !p.trim().is_empty()) {
+                    ports.push(p.trim().parse().map_err(|e| format!("invalid port {}: {}", p.trim(), e))?);
+                }
+            }
             _ => {}
         }
 
         match args.ethernet {
-            Some(t) => ethernet = t,
+            Some(t) => {
+                ethernet.clear();
+                for name in t.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+                    if !ethernet.iter().any(|e| e == name) {
+                        ethernet.push(name.to_string());
+                    }
+                }
+            }
             _ => {}
         }
 
-        Config{
-            host,
-            dtype,
-            port,
-            ethernet
+        if hosts.is_empty() && mirror.is_empty() && args.read_file.is_none() {
+            // 未指定时使用网卡上配置的ipv4/ipv6地址, 镜像端口等没有地址的网卡不按地址过滤
+            for name in &ethernet {
+                hosts.extend(capture::interface_addresses(name).map_err(|e| format!("{}: {}", name, e))?);
+            }
+        }
+
+        Ok(Config{
+            hosts,
+            dtype: args.dtype,
+            mirror,
+            dedup_window: args.dedup_window.unwrap_or(50),
+            ports,
+            ethernet,
+            read_file: args.read_file,
+            filter: args.filter,
+            max_sql_len: args.max_sql_len.unwrap_or(1048576),
+            max_sessions: args.max_sessions.unwrap_or(100000),
+            idle_timeout: args.idle_timeout.unwrap_or(300),
+            conn_idle_timeout: args.conn_idle_timeout.unwrap_or(28800),
+            stats_interval: args.stats_interval.unwrap_or(60),
+            threads: args.threads.unwrap_or(1).max(1),
+            async_capture: args.async_capture,
+            sink: args.sink.map(|t| runtime::Sink::parse(&t)).transpose()?,
+            metrics_addr: args.metrics_addr.map(|t| t.parse().map_err(|e| format!("invalid metrics address {}: {}", t, e))).transpose()?,
+            backend: args.backend.map(|t| Backend::parse(&t)).transpose()?.unwrap_or(Backend::Pcap),
+            ring_blocks: args.ring_blocks.unwrap_or(32).max(1),
+            fanout: args.fanout
+        })
+    }
+
+    ///
+    /// 抓包线程的数量, 离线文件只有一个
+    pub fn interfaces(&self) -> usize {
+        if self.read_file.is_some() { 1 } else { self.ethernet.len() }
+    }
+
+    pub fn session_limits(&self) -> session::SessionLimits {
+        session::SessionLimits{
+            max_entries: self.max_sessions,
+            idle_timeout: self.idle_timeout,
+            connection_idle_timeout: self.conn_idle_timeout
         }
     }
+
+    ///
+    /// 根据配置生成内核层的BPF过滤表达式, 只保留tcp、本机地址及指定端口的数据包
+    /// 镜像模式按服务端列表过滤, 没有本机地址时(离线文件、镜像端口)不按地址过滤
+    /// 用户指定的附加表达式以and的方式加入
+    /// ipv6存在扩展头部时BPF的tcp/port无法匹配, 这类包交由用户态解析判断
+    /// 带802.1Q/QinQ tag的帧需要使用vlan关键字偏移后再匹配
+    /// 每个vlan关键字都会使表达式中其后所有的偏移增加4字节, QinQ的分支嵌套在单层tag的分支中, 只再偏移一次
+    /// vlan关键字只能用于以太网, 其余链路层类型不加vlan的分支
+    pub fn bpf_filter(&self, link_type: LinkType) -> String {
+        let mut filter = String::from("(tcp or ip6 protochain 6)");
+        if !self.mirror.is_empty() {
+            let servers: Vec<String> = self.mirror.iter().map(|m| m.bpf_filter()).collect();
+            filter = format!("{} and ({})", filter, servers.join(" or "));
+        } else if !self.hosts.is_empty() {
+            let hosts: Vec<String> = self.hosts.iter().map(|h| format!("host {}", h)).collect();
+            filter = format!("{} and ({})", filter, hosts.join(" or "));
+        }
+        if !self.ports.is_empty(){
+            let mut ports: Vec<String> = self.ports.iter().map(|p| format!("port {}", p)).collect();
+            ports.push(String::from("(ip6 and ip6[6] != 6)"));
+            filter = format!("{} and ({})", filter, ports.join(" or "));
+        }
+        if let Some(extra) = &self.filter {
+            filter = format!("{} and ({})", filter, extra);
+        }
+        if link_type != LinkType::Ethernet {
+            return filter;
+        }
+        format!("({0}) or (vlan and ({0} or (vlan and {0})))", filter)
+    }
 }
 
 pub fn op_run() -> std::result::Result<(), Box<dyn Error>> {
     let args = Opt::from_args();
-    let conf = Config::new(args);
-    let mut all_session_info = session::AllSessionInfo::new();
-    let devices = Device::list().unwrap();
-    'all: for device in devices{
-        if &device.name == &conf.ethernet {
-            let mut cap = Capture::from_device(device).unwrap()
-                .promisc(true)
-                .snaplen(65535).open().unwrap();
+    let conf = Config::new(args)?;
+    if conf.dtype.is_some() && !conf.mirror.is_empty() {
+        return Err("--dtype can not be used with --mirror".into());
+    }
+    if conf.dtype.is_some() && conf.hosts.is_empty() {
+        return Err("--dtype requires the local address, use --host".into());
+    }
+    if conf.backend == Backend::AfPacket && (conf.async_capture || conf.read_file.is_some()) {
+        return Err("afpacket backend does not support --async or --read-file".into());
+    }
+    if !conf.async_capture && (conf.sink.is_some() || conf.metrics_addr.is_some()) {
+        return Err("--sink and --metrics-addr require --async".into());
+    }
+    if conf.async_capture {
+        return runtime::op_async_run(&conf);
+    }
+    let mut dispatcher = pipeline::Dispatcher::new(&conf, None)?;
+    let started = Instant::now();
+    let mut stats = CaptureStats::default();
+    match &conf.read_file {
+        Some(file) => {
+            // 离线模式, 读取tcpdump等工具保存的pcap/pcapng文件, 时间使用包中记录的时间戳
+            let mut cap = Capture::from_file(file)?;
+            set_filter(&mut cap, &conf)?;
+            op_capture(&mut PcapSource::new(cap)?, None, &conf, &mut dispatcher, &mut stats)?;
+        }
+        None => {
+            let mut sources = vec![];
+            for name in &conf.ethernet {
+                sources.push((Arc::from(name.as_str()), open_source(&conf, name)?));
+            }
             //let mut sfile = cap.savefile("acc.pcap").unwrap();
+            if sources.len() == 1 {
+                let (interface, mut source) = sources.remove(0);
+                op_capture(source.as_mut(), Some(interface), &conf, &mut dispatcher, &mut stats)?;
+            } else {
+                dispatcher = op_capture_all(sources, &conf, dispatcher, &mut stats)?;
+            }
+        }
+    }
+    eprintln!("{:?}", stats);
+    dispatcher.finish();
+    let elapsed = started.elapsed().as_secs_f64();
+    eprintln!("elapsed: {:.3}s, {:.0} packets/s", elapsed, stats.packets as f64 / elapsed.max(1e-6));
+    Ok(())
+}
 
-            'inner: while let Ok(packet) = cap.next() {
-                if packet.header.len < 73{                                                          // 判断是否为ack/syc包大小
-                    continue 'inner;
-                }
-                let mut my_packet = packet::StreamPacket::new(&packet)?;               // 解析网络包协议部分内容
+///
+/// 每个网卡使用一个抓包线程, 解析后分发到共用的worker, 所有网卡的session合并到同一组表中
+/// 任一网卡出错时立即输出错误, 其余网卡继续抓包直到结束后再返回该错误
+fn op_capture_all(sources: Vec<(Arc<str>, Box<dyn PacketSource + Send>)>, conf: &Config, dispatcher: pipeline::Dispatcher,
+                  stats: &mut CaptureStats) -> std::result::Result<pipeline::Dispatcher, Box<dyn Error>> {
+    let (dispatcher, shared) = dispatcher.share(sources.len());
+    let mut handles = vec![];
+    for ((interface, mut source), mut shared) in sources.into_iter().zip(shared) {
+        let conf = conf.clone();
+        handles.push(thread::Builder::new().name(format!("capture-{}", interface)).spawn(move || {
+            let mut stats = CaptureStats::default();
+            let result = op_capture(source.as_mut(), Some(interface.clone()), &conf, &mut shared, &mut stats)
+                .map_err(|e| format!("{}: {}", interface, e));
+            if let Err(e) = &result {
+                eprintln!("{}", e);                                                         // 出错时立即输出, 不等待其余网卡结束
+            }
+            eprintln!("{}: {:?}", interface, stats);
+            result.map(|_| stats)
+        })?);
+    }
+    let mut error = None;
+    for handle in handles {
+        match handle.join() {
+            Ok(Ok(v)) => stats.merge(&v),
+            Ok(Err(e)) => error = Some(e),
+            Err(_) => error = Some(String::from("capture thread panicked"))
+        }
+    }
+    match error {
+        Some(e) => Err(e.into()),
+        None => Ok(dispatcher.unshare())
+    }
+}
 
-                if !check_ack_syn(&my_packet){                                                      // 根据flag头再次判断是否为ack/syc包
-                    continue 'inner;
-                }
+///
+/// 打开指定的网卡并设置BPF过滤
+fn open_device(conf: &Config, name: &str) -> std::result::Result<Capture<Active>, Box<dyn Error>> {
+    for device in Device::list()?{
+        if device.name == name {
+            let mut cap = Capture::from_device(device)?
+                .promisc(true)
+                .snaplen(65535).open()?;
+            set_filter(&mut cap, conf)?;                                                    // 在内核层过滤掉不需要的数据包
+            return Ok(cap);
+        }
+    }
+    Err(format!("device not found: {}", name).into())
+}
 
+///
+/// 按抓包句柄的链路层类型设置BPF过滤, 不支持的类型按以太网处理, 由后续的解析报错
+fn set_filter<T: Activated + ?Sized>(cap: &mut Capture<T>, conf: &Config) -> std::result::Result<(), Box<dyn Error>> {
+    let link_type = LinkType::from_dlt(cap.get_datalink().0).unwrap_or(LinkType::Ethernet);
+    cap.filter(&conf.bpf_filter(link_type))?;
+    Ok(())
+}
 
-                //println!("{:?}",&my_packet.protocol_header);
-                if my_packet.check_port(&conf){                                                     // 判断数据流向端口是否为给定的端口
-                    //println!("{:?}, tell:{}, len:{}", my_packet.protocol_header, my_packet.data_cur.tell().unwrap(), my_packet.len);
-                    //sfile.write(&packet);
-                    let session_key = my_packet.set_stream_type(&conf)?;
-                    my_packet.get_mysql_protocol_header()?;                                             // 获取mysql协议header部分
-                    //println!("{:?}, {:?}, {:?}", my_packet.session_host_info, my_packet.s_type, my_packet.protocol_header);
-                    //println!("{:?}", all_session_info);
-                    my_packet.op_session_info(&session_key, &mut all_session_info)?;
-                }
+///
+/// 按配置的后端打开网卡
+fn open_source(conf: &Config, name: &str) -> std::result::Result<Box<dyn PacketSource + Send>, Box<dyn Error>> {
+    match conf.backend {
+        Backend::Pcap => Ok(Box::new(PcapSource::new(open_device(conf, name)?)?)),
+        #[cfg(target_os = "linux")]
+        Backend::AfPacket => Ok(Box::new(afpacket::AfPacketSource::new(name, &|v| conf.bpf_filter(v), conf.ring_blocks, conf.fanout)?)),
+        #[cfg(not(target_os = "linux"))]
+        Backend::AfPacket => Err("afpacket backend is only supported on linux".into()),
+    }
+}
+
+///
+/// 从抓包后端(网卡或离线文件)中循环读取数据包并解析, 解析后的包标记抓包的网卡
+/// 离线文件读取完毕后返回
+fn op_capture<S: PacketSource + ?Sized>(source: &mut S, interface: Option<Arc<str>>, conf: &Config, dispatcher: &mut pipeline::Dispatcher,
+                                        stats: &mut CaptureStats) -> std::result::Result<(), Box<dyn Error>> {
+    let mut last_report = 0;                                                                // 最近一次输出计数信息的包时间
+    let link_type = source.link_type();
+    'inner: loop {
+        let packet = match source.next_packet()? {
+            Some(packet) => packet,
+            None => break 'inner
+        };
+        let mut my_packet = match decode_packet(&packet, link_type, conf, stats) {
+            Some(v) => v,
+            None => continue 'inner
+        };
+        my_packet.interface = interface.clone();
+        let due = report_due(&mut last_report, &my_packet.ts, conf.stats_interval);
+        dispatcher.dispatch(my_packet);
+        if due {
+            // 包数据借用自抓包后端, 分发之后才能读取后端的计数
+            stats.dropped = source.dropped();
+            match &interface {
+                Some(name) if conf.interfaces() > 1 => eprintln!("{}: {:?}", name, stats),
+                _ => eprintln!("{:?}", stats)
             }
+            dispatcher.report();
         }
     }
+    stats.dropped = source.dropped();
     Ok(())
 }
 
+///
+/// 按包时间判断是否到了输出计数信息的间隔, interval为0时不输出
+fn report_due(last_report: &mut u64, ts: &packet::UnixTime, interval: u64) -> bool {
+    if *last_report == 0 {
+        *last_report = ts.tv_sec;
+    } else if interval > 0 && ts.tv_sec >= *last_report + interval {
+        *last_report = ts.tv_sec;
+        return true;
+    }
+    false
+}
+
+///
+/// 解析网络包并过滤, 返回需要处理的包
+fn decode_packet<'a>(packet: &RawPacket<'a>, link_type: LinkType, conf: &Config, stats: &mut CaptureStats) -> Option<packet::StreamPacket<'a>> {
+    stats.packets += 1;
+    let my_packet = match packet::StreamPacket::new(packet, link_type) {                     // 解析网络包协议部分内容
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            // 非ip/tcp的包
+            stats.skipped += 1;
+            return None;
+        }
+        Err(_) => {
+            // 畸形包只做计数
+            stats.malformed += 1;
+            return None;
+        }
+    };
+    if !check_ack_syn(&my_packet){                                                          // 根据flag头判断是否需要处理
+        return None;
+    }
+    if !my_packet.check_port(conf){                                                         // 判断数据流向端口是否为给定的端口
+        return None;
+    }
+    if !my_packet.check_mirror_server(conf){                                                // 镜像模式下判断是否为需要审计的服务端
+        return None;
+    }
+    Some(my_packet)
+}
+
+///
+/// 抓包过程中的计数信息
+#[derive(Debug, Default)]
+pub struct CaptureStats {
+    pub packets: u64,           // 读取到的包总数
+    pub skipped: u64,           // 非ip/tcp的包
+    pub malformed: u64,         // 无法解析的畸形包
+    pub dropped: u64,           // 抓包后端丢弃的包
+}
+
+impl CaptureStats {
+    ///
+    /// 累加其他抓包线程的计数
+    pub fn merge(&mut self, other: &CaptureStats) {
+        self.packets += other.packets;
+        self.skipped += other.skipped;
+        self.malformed += other.malformed;
+        self.dropped += other.dropped;
+    }
+}
+
 ///
 /// 判断协议类型
+/// syn/fin/rst用于跟踪连接的建立和断开, syn同时用于获取初始序列号, 带数据的包进入tcp重组
+/// 纯ack包用于跟踪反方向已被确认的序列号, 及时跳过抓包丢失的缺口
 fn check_ack_syn(my_packet: &packet::StreamPacket) -> bool{
-    match my_packet.packet_flag{
-        0x12 => false,
-        0x02 => false,
-        0x18 => true,
-        _ => false
-    }
+    my_packet.packet_flag & (TCP_SYN | TCP_FIN | TCP_RST | TCP_ACK) != 0 || !my_packet.payload.is_empty()
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(args: &[&str]) -> Config {
+        let mut argv = vec!["testaa", "-r", "test.pcap"];
+        argv.extend_from_slice(args);
+        Config::new(Opt::from_iter(argv)).unwrap()
+    }
+
+    #[test]
+    fn bpf_filter_vlan_branches() {
+        let conf = config(&["-p", "3306"]);
+        let base = "(tcp or ip6 protochain 6) and (port 3306 or (ip6 and ip6[6] != 6))";
+        // 每个vlan关键字使其后的偏移增加4字节, QinQ的分支在单层tag的分支内只再偏移一次
+        assert_eq!(conf.bpf_filter(LinkType::Ethernet),
+                   format!("({0}) or (vlan and ({0} or (vlan and {0})))", base));
+        for link_type in &[LinkType::Raw, LinkType::Null, LinkType::Loop, LinkType::LinuxSll, LinkType::LinuxSll2] {
+            assert_eq!(conf.bpf_filter(*link_type), base);
+        }
+    }
+
+    #[test]
+    fn bpf_filter_hosts_mirror_and_extra_filter() {
+        let conf = config(&["-h", "10.0.0.1,[2001:db8::1]", "-f", "not port 22"]);
+        assert_eq!(conf.bpf_filter(LinkType::Raw),
+                   "(tcp or ip6 protochain 6) and (host 10.0.0.1 or host 2001:db8::1) and (not port 22)");
+        let conf = config(&["--mirror", "10.0.0.5:3306,10.0.1.0/24", "-h", "10.0.0.1"]);
+        assert_eq!(conf.bpf_filter(LinkType::Raw),
+                   "(tcp or ip6 protochain 6) and ((host 10.0.0.5 and port 3306) or net 10.0.1.0/24)");
+    }
+}
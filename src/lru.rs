@@ -0,0 +1,69 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/4/10
+*/
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+///
+/// 按最近访问顺序记录键, 用于空闲超时及超过容量时淘汰最久未访问的条目
+/// 只记录顺序, 条目本身由调用方保存
+#[derive(Debug)]
+pub struct LruIndex<K: Hash + Eq + Clone> {
+    tick: u64,                              // 访问序号, 每次访问递增
+    order: BTreeMap<u64, K>,                // 访问序号 -> 键
+    entries: HashMap<K, (u64, u64)>,        // 键 -> (访问序号, 最后访问时间秒)
+}
+
+impl<K: Hash + Eq + Clone> Default for LruIndex<K> {
+    fn default() -> Self {
+        LruIndex { tick: 0, order: BTreeMap::new(), entries: HashMap::new() }
+    }
+}
+
+impl<K: Hash + Eq + Clone> LruIndex<K> {
+    pub fn new() -> LruIndex<K> {
+        LruIndex::default()
+    }
+
+    ///
+    /// 记录一次访问, now为当前时间秒
+    pub fn touch(&mut self, key: &K, now: u64) {
+        self.tick += 1;
+        if let Some((tick, _)) = self.entries.insert(key.clone(), (self.tick, now)) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, key.clone());
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some((tick, _)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    ///
+    /// 弹出最久未访问的键
+    pub fn pop_oldest(&mut self) -> Option<K> {
+        let tick = *self.order.keys().next()?;
+        let key = self.order.remove(&tick)?;
+        self.entries.remove(&key);
+        Some(key)
+    }
+
+    ///
+    /// 弹出所有超过timeout秒未访问的键
+    pub fn pop_idle(&mut self, now: u64, timeout: u64) -> Vec<K> {
+        let mut idle = vec![];
+        while let Some((_, key)) = self.order.iter().next() {
+            let last = self.entries.get(key).map(|v| v.1).unwrap_or(0);
+            if last + timeout > now {
+                break;
+            }
+            if let Some(key) = self.pop_oldest() {
+                idle.push(key);
+            }
+        }
+        idle
+    }
+}
@@ -2,18 +2,25 @@
 @author: xiao cai niao
 @datetime: 2020/3/28
 */
-mod protocol;
+pub mod protocol;
+pub mod network;
+use std::borrow::Cow;
 use std::error::Error;
-use std::io::{Cursor, Seek, Read};
-use byteorder::{ReadBytesExt, BigEndian};
-use std::io;
-use crate::{Config, Tell};
+use std::io::Cursor;
+use crate::Config;
 use crate::session;
-use crate::session::{SessionInfo, SessionHostInfo};
+use crate::session::{SessionInfo, SessionHostInfo, ResultState};
+use crate::flow::FlowKey;
+use crate::capture::RawPacket;
+use crate::direction::{ServerTable, is_mirror_server};
 use std::convert::TryInto;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use network::{TcpIpHeader, LinkType};
+use protocol::MysqlPacket;
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct UnixTime{
     pub tv_sec: u64,
     pub tv_usec: u64
@@ -26,38 +33,17 @@ impl UnixTime{
             tv_usec: ts.tv_usec.try_into()?,
         })
     }
-}
 
-
-///
-/// 解析出的ip结构
-///
-#[derive(Debug, Clone)]
-pub struct Ip{
-    pub ip_first: u8,
-    pub ip_two: u8,
-    pub ip_three: u8,
-    pub ip_four: u8
-}
-impl Ip{
-    pub fn new(cur: &mut Cursor<Vec<u8>>) -> Ip{
-        let ip_first = cur.read_u8().unwrap();
-        let ip_two = cur.read_u8().unwrap();
-        let ip_three = cur.read_u8().unwrap();
-        let ip_four = cur.read_u8().unwrap();
-        Ip {
-            ip_first,
-            ip_two,
-            ip_three,
-            ip_four
-        }
-    }
-
-    pub fn format_ip(&self) -> String{
-        format!("{}.{}.{}.{}", self.ip_first.clone(), self.ip_two.clone(), self.ip_three.clone(),self.ip_four.clone())
+    ///
+    /// 距离start的秒数
+    pub fn seconds_since(&self, start: &UnixTime) -> f64{
+        let end = (self.tv_sec * 1_000_000 + self.tv_usec) as i64;
+        let start = (start.tv_sec * 1_000_000 + start.tv_usec) as i64;
+        (end - start) as f64 / 1_000_000.0
     }
 }
 
+
 ///
 /// 数据包方向(请求/回应)
 #[derive(Clone, Debug)]
@@ -66,6 +52,17 @@ pub enum StreamType{
     Response
 }
 
+impl StreamType{
+    ///
+    /// 反方向数据流的类型
+    pub fn reverse(&self) -> StreamType{
+        match self{
+            StreamType::Request => StreamType::Response,
+            StreamType::Response => StreamType::Request
+        }
+    }
+}
+
 ///
 /// mysql协议类型
 #[derive(Debug, Clone)]
@@ -93,44 +90,62 @@ pub struct  MysqlProtocolHeader{
 }
 
 
-pub struct StreamPacket{
-    pub data_cur: Cursor<Vec<u8>>,
+///
+/// 解析后的网络包, payload借用pcap的缓冲区, 只有需要保留的数据(重组缓存、mysql包)才复制
+/// 需要发送到其他线程时通过into_owned复制tcp数据部分
+pub struct StreamPacket<'a>{
+    pub payload: Cow<'a, [u8]>,                     // tcp数据部分
+    pub data_cur: Cursor<Vec<u8>>,                  // 当前解析的mysql逻辑包
+    pub data_truncated: bool,                       // 当前mysql逻辑包的payload未全部保留
+    pub max_sql_len: usize,                         // sql文本最多保留的字节数
     pub packet_flag: u8,
+    pub seq: u32,
+    pub ack: u32,
+    pub ip_id: Option<u16>,
     pub ts: UnixTime,
     pub len: u32,
-    pub source: Ip,
-    pub destination: Ip,
+    pub source: IpAddr,
+    pub destination: IpAddr,
     pub source_port: u16,
     pub destination_port: u16,
+    pub vlan_id: Option<u16>,
+    pub outer_vlan_id: Option<u16>,
+    pub interface: Option<Arc<str>>,                // 抓包的网卡, 离线文件为None
     pub s_type: StreamType,
     pub session_host_info: SessionHostInfo,
     pub protocol_header: MysqlProtocolHeader,
 }
 
-impl StreamPacket{
-    pub fn new(packet: &pcap::Packet) -> Result<StreamPacket, Box<dyn Error>>{
-        let ts = UnixTime::new(&packet.header.ts)?;
-        let len= packet.header.len;
-
-        let mut cur = Cursor::new(packet.data.to_vec());
-        cur.seek(io::SeekFrom::Current(26)).unwrap();
-        let source = Ip::new(&mut cur);
-        let destination = Ip::new(&mut cur);
-        let source_port = cur.read_u16::<BigEndian>().unwrap();
-        let destination_port = cur.read_u16::<BigEndian>().unwrap();
+impl<'a> StreamPacket<'a>{
+    ///
+    /// 解析网络包的ip/tcp部分, 非ipv4/ipv6承载的tcp包返回None, 畸形包返回错误
+    /// 不复制包数据, payload为tcp数据部分的切片
+    pub fn new(packet: &RawPacket<'a>, link_type: LinkType) -> Result<Option<StreamPacket<'a>>, Box<dyn Error>>{
+        let ts = packet.ts.clone();
+        let len= packet.len;
 
-        cur.seek(io::SeekFrom::Current(9)).unwrap();
-        let packet_flag = cur.read_u8().unwrap();
-        cur.seek(io::SeekFrom::Current(18))?;
-        Ok(StreamPacket{
-            data_cur: cur,
-            packet_flag,
+        let header = match TcpIpHeader::new(packet.data, link_type)?{
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        Ok(Some(StreamPacket{
+            payload: Cow::Borrowed(&packet.data[header.payload_start..header.payload_end]),
+            data_cur: Cursor::new(vec![]),
+            data_truncated: false,
+            max_sql_len: usize::MAX,
+            packet_flag: header.packet_flag,
+            seq: header.seq,
+            ack: header.ack,
+            ip_id: header.ip_id,
             ts,
             len,
-            source,
-            destination,
-            source_port,
-            destination_port,
+            source: header.source,
+            destination: header.destination,
+            source_port: header.source_port,
+            destination_port: header.destination_port,
+            vlan_id: header.vlan_id,
+            outer_vlan_id: header.outer_vlan_id,
+            interface: None,
             s_type: StreamType::Request,
             session_host_info: SessionHostInfo::new(),
             protocol_header: MysqlProtocolHeader {
@@ -138,62 +153,132 @@ impl StreamPacket{
                 seq_id: 0,
                 protocol_type: MysqlProtocol::Null
             }
-        })
+        }))
+    }
+
+    ///
+    /// 复制tcp数据部分, 不再借用pcap的缓冲区
+    pub fn into_owned(self) -> StreamPacket<'static>{
+        StreamPacket{
+            payload: Cow::Owned(self.payload.into_owned()),
+            data_cur: self.data_cur,
+            data_truncated: self.data_truncated,
+            max_sql_len: self.max_sql_len,
+            packet_flag: self.packet_flag,
+            seq: self.seq,
+            ack: self.ack,
+            ip_id: self.ip_id,
+            ts: self.ts,
+            len: self.len,
+            source: self.source,
+            destination: self.destination,
+            source_port: self.source_port,
+            destination_port: self.destination_port,
+            vlan_id: self.vlan_id,
+            outer_vlan_id: self.outer_vlan_id,
+            interface: self.interface,
+            s_type: self.s_type,
+            session_host_info: self.session_host_info,
+            protocol_header: self.protocol_header
+        }
+    }
+
+    ///
+    /// 替换数据部分, 用于tcp重组后逐个解析mysql逻辑包
+    pub fn set_payload(&mut self, mysql_packet: MysqlPacket) {
+        self.data_cur = Cursor::new(mysql_packet.data);
+        self.data_truncated = mysql_packet.truncated;
+    }
+
+    ///
+    /// 单方向数据流的键, 用于tcp重组, reverse为true时为相反方向的键
+    pub fn stream_key(&self, reverse: bool) -> FlowKey {
+        let key = FlowKey::new(SocketAddr::new(self.source, self.source_port),
+                               SocketAddr::new(self.destination, self.destination_port),
+                               self.vlan_id, self.outer_vlan_id);
+        if reverse { key.reverse() } else { key }
     }
 
     ///
     /// 判断获取到的数据流是请求还是响应
+    /// 镜像模式按服务端列表判断, 指定了本机所属方向时按本机地址判断, 否则自动判断哪一端为服务端
     ///
-    pub fn set_stream_type(&mut self,conf: &Config) -> Result<String, Box<dyn Error>> {
-        if conf.dtype == String::from("src"){
-            return self.check_src(conf);
-        }else {
-            return self.check_des(conf);
+    pub fn set_stream_type(&mut self,conf: &Config, servers: &mut ServerTable) -> Result<FlowKey, Box<dyn Error>> {
+        if !conf.mirror.is_empty() {
+            self.check_mirror(conf, servers);
+            return Ok(self.session_key());
+        }
+        match conf.dtype.as_deref() {
+            Some("src") => self.check_src(conf),
+            Some(_) => self.check_des(conf),
+            None => {
+                let destination_is_server = servers.destination_is_server(self, conf);
+                self.set_direction(destination_is_server);
+            }
         }
+        Ok(self.session_key())
+    }
+
+    ///
+    /// 镜像模式, 两端都不是本机, 匹配服务端列表的一端为服务端
+    /// 两端都匹配时(服务端之间的连接)自动判断
+    fn check_mirror(&mut self, conf: &Config, servers: &mut ServerTable) {
+        let destination_is_server = match (is_mirror_server(conf, &self.source, self.source_port),
+                                           is_mirror_server(conf, &self.destination, self.destination_port)) {
+            (true, false) => false,
+            (false, true) => true,
+            _ => servers.destination_is_server(self, conf)
+        };
+        self.set_direction(destination_is_server);
+        let info = &mut self.session_host_info;
+        info.server = Some(SocketAddr::new(info.destination, info.destination_port));
+    }
+
+    ///
+    /// 镜像模式下只处理至少一端在服务端列表中的包
+    pub fn check_mirror_server(&self, conf: &Config) -> bool {
+        conf.mirror.is_empty()
+            || is_mirror_server(conf, &self.source, self.source_port)
+            || is_mirror_server(conf, &self.destination, self.destination_port)
     }
 
     ///
     /// 监听模式为src的情况， 即本机为源
-    fn check_src(&mut self,conf: &Config) -> Result<String, Box<dyn Error>>{
-        let mut session_key = String::from("");
-        if &self.source.format_ip() == &conf.host{
-            session_key = format!("{}:{}", conf.host.clone(), self.source_port.clone());
-            self.session_host_info.set(conf.host.clone(),
-                                       self.destination.format_ip(),
-                                       self.source_port.clone(),
-                                       self.destination_port.clone());
-            self.s_type = StreamType::Request;
-        }else {
-            session_key = format!("{}:{}", self.destination.format_ip(), self.destination_port.clone());
-            self.session_host_info.set(self.destination.format_ip(),
-                                       self.source.format_ip(),
-                                       self.destination_port.clone(),
-                                       self.source_port.clone());
-            self.s_type = StreamType::Response;
-        }
-        Ok(session_key)
+    fn check_src(&mut self,conf: &Config) {
+        self.set_direction(conf.hosts.contains(&self.source));
     }
 
     ///
     /// 监听模式为des的情况， 即本机为目标
-    fn check_des(&mut self,conf: &Config) -> Result<String, Box<dyn Error>>{
-        let mut session_key = String::from("");
-        if self.destination.format_ip() == conf.host{
-            session_key = format!("{}:{}", self.source.format_ip(), self.source_port.clone());
-            self.session_host_info.set(self.source.format_ip(),
-                                       self.destination.format_ip(),
-                                       self.source_port.clone(),
-                                       self.destination_port.clone());
+    fn check_des(&mut self,conf: &Config) {
+        self.set_direction(conf.hosts.contains(&self.destination));
+    }
+
+    ///
+    /// 目标地址为服务端时为请求, 否则为响应
+    fn set_direction(&mut self, destination_is_server: bool) {
+        if destination_is_server{
+            self.session_host_info.set(self.source,
+                                       self.destination,
+                                       self.source_port,
+                                       self.destination_port);
             self.s_type = StreamType::Request;
         }else {
-            session_key = format!("{}:{}", self.destination.format_ip(), self.destination_port.clone());
-            self.session_host_info.set(self.destination.format_ip(),
-                                       self.source.format_ip(),
-                                       self.destination_port.clone(),
-                                       self.source_port.clone());
+            self.session_host_info.set(self.destination,
+                                       self.source,
+                                       self.destination_port,
+                                       self.source_port);
             self.s_type = StreamType::Response;
         }
-        Ok(session_key)
+    }
+
+    ///
+    /// 生成session键, 客户端->服务端方向的四元组
+    fn session_key(&self) -> FlowKey{
+        let info = &self.session_host_info;
+        FlowKey::new(SocketAddr::new(info.source, info.source_port),
+                     SocketAddr::new(info.destination, info.destination_port),
+                     self.vlan_id, self.outer_vlan_id)
     }
 
     ///
@@ -205,12 +290,12 @@ impl StreamPacket{
     }
 
     pub fn check_port(&self, conf: &Config) -> bool{
-        if conf.port == 0{
+        if conf.ports.is_empty(){
             return true;
         }
-        else if self.source_port == conf.port{
+        else if conf.ports.contains(&self.source_port){
             return true;
-        }else if self.destination_port == conf.port{
+        }else if conf.ports.contains(&self.destination_port){
             return true;
         }else{
             return false;
@@ -220,55 +305,87 @@ impl StreamPacket{
     ///
     /// 操作session数据
     /// 包含解析内容，如果结束类的包将打印
-    pub fn op_session_info(&mut self, session_key: &String, all_session: &mut session::AllSessionInfo) -> Result<(), Box<dyn Error>>{
+    /// 已存在的session直接在表中修改, 不做复制
+    pub fn op_session_info(&mut self, session_key: &FlowKey, all_session: &mut session::AllSessionInfo) -> Result<(), Box<dyn Error>>{
         match self.s_type{
             StreamType::Request => {
-                match all_session.aluino.get(session_key){
+                if let MysqlProtocol::ComQuit = self.protocol_header.protocol_type{
+                    all_session.connection_quit(session_key, &self.ts);
+                }
+                // 请求包不刷新等待时间, 返回丢失时由返回方向跳过缺口或空闲超时淘汰
+                match all_session.aluino.get_mut(session_key){
                     Some(v) => {
-                        let mut local_session = v.clone();
                         if v.connection_pre{
                             // 准备创建连接
-                            if !local_session.unpacket_handshake_response(self)?{
+                            let complete = v.unpacket_handshake_response(self)?;
+                            if let Some(handshake_response) = v.handshake_response.clone(){
+                                all_session.connection_login(session_key, handshake_response, &self.ts);
+                            }
+                            if !complete{
                                 all_session.remove(session_key);
-                            }else {
-                                local_session.insert(all_session, session_key)?;
                             }
+                        }else if v.is_ok && self.protocol_header.seq_id == 0 {
+                            // 前一个请求还未返回时的新请求, 排队等待
+                            let mut new_session = SessionInfo::new(self)?;
+                            let protocol_type = self.protocol_header.protocol_type.clone();
+                            protocol_type.protocol_unpacket(self, &mut new_session)?;
+                            all_session.push_pipelined(session_key, new_session);
                         }else {
-                            local_session.session_unpacket(self, session_key, all_session)?;
+                            let protocol_type = self.protocol_header.protocol_type.clone();
+                            protocol_type.protocol_unpacket(self, v)?;
                         }
                     }
                     None => {
-                        let mut new_session = SessionInfo::new(self)?;
+                        let new_session = SessionInfo::new(self)?;
                         new_session.session_unpacket(self, session_key, all_session)?;
                     }
                 }
+                all_session.connection_request(session_key);
             }
             StreamType::Response => {
+                if let Some(v) = all_session.get_mut(session_key){
+                    if v.result_state != ResultState::Null{
+                        // 结果集中的包, 首字节不能作为包类型判断
+                        if v.response_unpacket(self)?{
+                            all_session.complete(session_key);
+                        }
+                        return Ok(());
+                    }
+                }
+                if let MysqlProtocol::HandshakePacket = self.protocol_header.protocol_type{
+                    if self.protocol_header.seq_id != 0 || all_session.aluino.contains_key(session_key){
+                        // 0x0a开头的返回也可能是10列结果集的列数包, 只有没有等待返回的请求且seq_id为0时才是握手包
+                        self.protocol_header.protocol_type = MysqlProtocol::TextResult;
+                    }
+                }
                 match self.protocol_header.protocol_type {
                     MysqlProtocol::HandshakePacket => {
                         //准备创建连接
                         let mut new_session = SessionInfo::new(self)?;
                         MysqlProtocol::HandshakePacket.protocol_unpacket(self, &mut new_session)?;
-                        new_session.insert(all_session, session_key)?;
+                        if let Some(handshake) = new_session.handshake.clone(){
+                            all_session.set_handshake(session_key, handshake);
+                        }
+                        new_session.insert(all_session, session_key);
                     }
                     _ => {
-                        match all_session.aluino.get(session_key){
+                        match all_session.get_mut(session_key){
                             Some(v) => {
                                 if v.connection_pre {
                                     if v.create_conn_auth{
                                         // 已收到连接验证信息，判断返回包类型， 如果为结束类型包将做打印和删除操作
                                         if v.server_response.check_response_packet(&self.protocol_header.protocol_type){
-                                            v.out_info();
-                                            all_session.remove(session_key);
+                                            all_session.complete(session_key);
                                         }
                                     }
                                     // 已存在准备连接的session信息但未收到连接验证信息， 不做处理
                                     return Ok(())
                                 }
-                                else if v.seq_id + 1 == self.protocol_header.seq_id{
+                                else if v.seq_id.wrapping_add(1) == self.protocol_header.seq_id{
                                     // 包seq_id为顺序， 表示正常, 进行解包
-                                    let mut local_session = v.clone();
-                                    local_session.session_unpacket(self, session_key, all_session)?;
+                                    if v.response_unpacket(self)?{
+                                        all_session.complete(session_key);
+                                    }
                                 }
                             }
                             None => {}
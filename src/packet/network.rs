@@ -0,0 +1,646 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/4/2
+*/
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::convert::TryInto;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const NULL_HEADER_LEN: usize = 4;
+const SLL_HEADER_LEN: usize = 16;
+const SLL2_HEADER_LEN: usize = 20;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;             // 802.1Q
+const ETHERTYPE_QINQ: u16 = 0x88a8;             // 802.1ad
+const ETHERTYPE_QINQ_OLD: u16 = 0x9100;         // 早期QinQ实现使用的tpid
+const VLAN_TAG_LEN: usize = 4;
+const IPV6_HEADER_LEN: usize = 40;
+
+// tcp标志位
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_ACK: u8 = 0x10;
+
+// ip协议号/ipv6 next header
+const IP_PROTOCOL_TCP: u8 = 6;
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AH: u8 = 51;
+const IPV6_DESTINATION: u8 = 60;
+const IPV6_MOBILITY: u8 = 135;
+const IPV6_HIP: u8 = 139;
+const IPV6_SHIM6: u8 = 140;
+
+///
+/// 网络层解析错误, 出现该错误的包为畸形包, 只做计数不做处理
+#[derive(Debug, Clone)]
+pub enum DecodeError{
+    Truncated(&'static str),        // 包长度不足以容纳对应的头部
+    BadIpHeader(&'static str),      // ip头部字段不合法
+    BadTcpHeader(&'static str),     // tcp头部字段不合法
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated(s) => write!(f, "truncated packet: {}", s),
+            DecodeError::BadIpHeader(s) => write!(f, "bad ip header: {}", s),
+            DecodeError::BadTcpHeader(s) => write!(f, "bad tcp header: {}", s),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+///
+/// 支持的数据链路层类型, 由Capture::get_datalink()获取的DLT值转换
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkType{
+    Ethernet,           // DLT_EN10MB
+    Null,               // DLT_NULL, 4字节协议族, 使用抓包机器的字节序
+    Loop,               // DLT_LOOP, 4字节协议族, 网络字节序
+    Raw,                // DLT_RAW/LINKTYPE_IPV4/LINKTYPE_IPV6, 没有链路层头部
+    LinuxSll,           // DLT_LINUX_SLL, 在any网卡上抓包
+    LinuxSll2,          // DLT_LINUX_SLL2
+}
+
+impl LinkType{
+    pub fn from_dlt(dlt: i32) -> Option<LinkType>{
+        match dlt{
+            1 => Some(LinkType::Ethernet),
+            0 => Some(LinkType::Null),
+            108 => Some(LinkType::Loop),
+            12 | 14 | 101 | 228 | 229 => Some(LinkType::Raw),
+            113 => Some(LinkType::LinuxSll),
+            276 => Some(LinkType::LinuxSll2),
+            _ => None
+        }
+    }
+}
+
+///
+/// ethernet/ipv4/tcp头部解析出的信息
+/// payload_start、payload_end为tcp数据部分在原始帧中的位置, 已去除以太网填充字节
+#[derive(Debug, Clone)]
+pub struct TcpIpHeader{
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub packet_flag: u8,
+    pub seq: u32,
+    pub ack: u32,                           // 确认号, 只有带ACK标志时有效
+    pub ip_id: Option<u16>,                 // ipv4的identification, ipv6没有该字段
+    pub payload_start: usize,
+    pub payload_end: usize,
+    pub vlan_id: Option<u16>,               // 最内层的vlan id, 单层tag时即为该tag
+    pub outer_vlan_id: Option<u16>,         // QinQ时外层的vlan id
+}
+
+impl TcpIpHeader{
+    ///
+    /// 根据链路层类型解析数据帧, 非ip/tcp的包或ip分片返回None
+    pub fn new(data: &[u8], link_type: LinkType) -> Result<Option<TcpIpHeader>, DecodeError>{
+        match link_type{
+            LinkType::Ethernet => {
+                if data.len() < ETHERNET_HEADER_LEN{
+                    return Err(DecodeError::Truncated("ethernet header"));
+                }
+                let ether_type = u16::from_be_bytes([data[12], data[13]]);
+                Self::parse_ether_type(data, ether_type, ETHERNET_HEADER_LEN)
+            }
+            LinkType::Null | LinkType::Loop => {
+                if data.len() < NULL_HEADER_LEN{
+                    return Err(DecodeError::Truncated("loopback header"));
+                }
+                let family_bytes = [data[0], data[1], data[2], data[3]];
+                let family = if link_type == LinkType::Loop {
+                    u32::from_be_bytes(family_bytes)
+                } else if u32::from_le_bytes(family_bytes) <= 0xff {
+                    // DLT_NULL使用抓包机器的字节序, 文件可能来自不同字节序的机器
+                    u32::from_le_bytes(family_bytes)
+                } else {
+                    u32::from_be_bytes(family_bytes)
+                };
+                match family{
+                    // AF_INET
+                    2 => Self::parse_ipv4(data, NULL_HEADER_LEN),
+                    // 各系统的AF_INET6: linux 10, bsd 24, freebsd 28, darwin 30
+                    10 | 24 | 28 | 30 => Self::parse_ipv6(data, NULL_HEADER_LEN),
+                    _ => Ok(None)
+                }
+            }
+            LinkType::Raw => {
+                if data.is_empty(){
+                    return Err(DecodeError::Truncated("raw ip header"));
+                }
+                match data[0] >> 4{
+                    4 => Self::parse_ipv4(data, 0),
+                    6 => Self::parse_ipv6(data, 0),
+                    _ => Err(DecodeError::BadIpHeader("unknown ip version"))
+                }
+            }
+            LinkType::LinuxSll => {
+                if data.len() < SLL_HEADER_LEN{
+                    return Err(DecodeError::Truncated("linux sll header"));
+                }
+                let ether_type = u16::from_be_bytes([data[14], data[15]]);
+                Self::parse_ether_type(data, ether_type, SLL_HEADER_LEN)
+            }
+            LinkType::LinuxSll2 => {
+                if data.len() < SLL2_HEADER_LEN{
+                    return Err(DecodeError::Truncated("linux sll2 header"));
+                }
+                let ether_type = u16::from_be_bytes([data[0], data[1]]);
+                Self::parse_ether_type(data, ether_type, SLL2_HEADER_LEN)
+            }
+        }
+    }
+
+    ///
+    /// 根据链路层给出的协议类型解析网络层
+    /// 存在802.1Q/QinQ tag时先剥离tag并记录vlan id
+    fn parse_ether_type(data: &[u8], ether_type: u16, offset: usize) -> Result<Option<TcpIpHeader>, DecodeError>{
+        let mut ether_type = ether_type;
+        let mut offset = offset;
+        let mut vlan_ids: Vec<u16> = vec![];
+        while ether_type == ETHERTYPE_VLAN || ether_type == ETHERTYPE_QINQ || ether_type == ETHERTYPE_QINQ_OLD{
+            if data.len() < offset + VLAN_TAG_LEN{
+                return Err(DecodeError::Truncated("vlan tag"));
+            }
+            vlan_ids.push(u16::from_be_bytes([data[offset], data[offset + 1]]) & 0x0fff);
+            ether_type = u16::from_be_bytes([data[offset + 2], data[offset + 3]]);
+            offset += VLAN_TAG_LEN;
+        }
+        let header = match ether_type{
+            ETHERTYPE_IPV4 => Self::parse_ipv4(data, offset)?,
+            ETHERTYPE_IPV6 => Self::parse_ipv6(data, offset)?,
+            _ => None
+        };
+        Ok(header.map(|mut h| {
+            h.vlan_id = vlan_ids.last().cloned();
+            if vlan_ids.len() > 1{
+                h.outer_vlan_id = vlan_ids.first().cloned();
+            }
+            h
+        }))
+    }
+
+    ///
+    /// 解析ipv4头部, 根据IHL获取头部长度, 根据total length去掉尾部填充
+    fn parse_ipv4(data: &[u8], offset: usize) -> Result<Option<TcpIpHeader>, DecodeError>{
+        let ip_data = &data[offset..];
+        if ip_data.len() < 20{
+            return Err(DecodeError::Truncated("ipv4 header"));
+        }
+        if ip_data[0] >> 4 != 4{
+            return Err(DecodeError::BadIpHeader("version is not 4"));
+        }
+        let header_len = ((ip_data[0] & 0x0f) as usize) * 4;
+        if header_len < 20{
+            return Err(DecodeError::BadIpHeader("ihl less than 5"));
+        }
+        if ip_data.len() < header_len{
+            return Err(DecodeError::Truncated("ipv4 options"));
+        }
+        let total_len = u16::from_be_bytes([ip_data[2], ip_data[3]]) as usize;
+        if total_len < header_len{
+            return Err(DecodeError::BadIpHeader("total length less than header length"));
+        }
+        let flags_fragment = u16::from_be_bytes([ip_data[6], ip_data[7]]);
+        if flags_fragment & 0x3fff != 0{
+            // 分片包(MF或fragment offset不为0), 无法单独解析tcp
+            return Ok(None);
+        }
+        if ip_data[9] != IP_PROTOCOL_TCP{
+            return Ok(None);
+        }
+        let source = IpAddr::V4(Ipv4Addr::new(ip_data[12], ip_data[13], ip_data[14], ip_data[15]));
+        let destination = IpAddr::V4(Ipv4Addr::new(ip_data[16], ip_data[17], ip_data[18], ip_data[19]));
+        // 抓包长度可能小于total length(snaplen截断), 以实际长度为准
+        let end = offset + total_len.min(ip_data.len());
+        let ip_id = u16::from_be_bytes([ip_data[4], ip_data[5]]);
+        Ok(Self::parse_tcp(data, offset + header_len, end, source, destination)?.map(|mut h| {
+            h.ip_id = Some(ip_id);
+            h
+        }))
+    }
+
+    ///
+    /// 解析ipv6头部, 沿next header跳过所有扩展头部直到tcp
+    /// 分片、ESP加密及未知的扩展头部返回None
+    fn parse_ipv6(data: &[u8], offset: usize) -> Result<Option<TcpIpHeader>, DecodeError>{
+        let ip_data = &data[offset..];
+        if ip_data.len() < IPV6_HEADER_LEN{
+            return Err(DecodeError::Truncated("ipv6 header"));
+        }
+        if ip_data[0] >> 4 != 6{
+            return Err(DecodeError::BadIpHeader("version is not 6"));
+        }
+        let payload_len = u16::from_be_bytes([ip_data[4], ip_data[5]]) as usize;
+        let source_octets: [u8; 16] = ip_data[8..24].try_into().map_err(|_| DecodeError::Truncated("ipv6 header"))?;
+        let destination_octets: [u8; 16] = ip_data[24..40].try_into().map_err(|_| DecodeError::Truncated("ipv6 header"))?;
+        let source = IpAddr::V6(Ipv6Addr::from(source_octets));
+        let destination = IpAddr::V6(Ipv6Addr::from(destination_octets));
+        // payload length为0时为jumbo payload, 以实际长度为准
+        let end = if payload_len == 0 {
+            data.len()
+        } else {
+            offset + (IPV6_HEADER_LEN + payload_len).min(ip_data.len())
+        };
+
+        let mut next_header = ip_data[6];
+        let mut cur = offset + IPV6_HEADER_LEN;
+        loop {
+            match next_header{
+                IP_PROTOCOL_TCP => {
+                    return Self::parse_tcp(data, cur, end, source, destination);
+                }
+                IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION | IPV6_MOBILITY | IPV6_HIP | IPV6_SHIM6 => {
+                    if end < cur + 8{
+                        return Err(DecodeError::Truncated("ipv6 extension header"));
+                    }
+                    next_header = data[cur];
+                    cur += (data[cur + 1] as usize + 1) * 8;
+                }
+                IPV6_FRAGMENT => {
+                    if end < cur + 8{
+                        return Err(DecodeError::Truncated("ipv6 fragment header"));
+                    }
+                    let fragment = u16::from_be_bytes([data[cur + 2], data[cur + 3]]);
+                    if fragment & 0xfff9 != 0{
+                        // 存在fragment offset或M标记, 无法单独解析tcp
+                        return Ok(None);
+                    }
+                    next_header = data[cur];
+                    cur += 8;
+                }
+                IPV6_AH => {
+                    if end < cur + 8{
+                        return Err(DecodeError::Truncated("ipv6 authentication header"));
+                    }
+                    next_header = data[cur];
+                    cur += (data[cur + 1] as usize + 2) * 4;
+                }
+                _ => return Ok(None)
+            }
+            if cur > end{
+                return Err(DecodeError::Truncated("ipv6 extension header"));
+            }
+        }
+    }
+
+    ///
+    /// 解析tcp头部, 根据data offset跳过所有选项
+    fn parse_tcp(data: &[u8], offset: usize, end: usize, source: IpAddr, destination: IpAddr) -> Result<Option<TcpIpHeader>, DecodeError>{
+        if end < offset + 20{
+            return Err(DecodeError::Truncated("tcp header"));
+        }
+        let tcp_data = &data[offset..end];
+        let source_port = u16::from_be_bytes([tcp_data[0], tcp_data[1]]);
+        let destination_port = u16::from_be_bytes([tcp_data[2], tcp_data[3]]);
+        let seq = u32::from_be_bytes([tcp_data[4], tcp_data[5], tcp_data[6], tcp_data[7]]);
+        let ack = u32::from_be_bytes([tcp_data[8], tcp_data[9], tcp_data[10], tcp_data[11]]);
+        let header_len = ((tcp_data[12] >> 4) as usize) * 4;
+        let packet_flag = tcp_data[13];
+        if header_len < 20{
+            return Err(DecodeError::BadTcpHeader("data offset less than 5"));
+        }
+        if tcp_data.len() < header_len{
+            return Err(DecodeError::Truncated("tcp options"));
+        }
+        Ok(Some(TcpIpHeader{
+            source,
+            destination,
+            source_port,
+            destination_port,
+            packet_flag,
+            seq,
+            ack,
+            ip_id: None,
+            payload_start: offset + header_len,
+            payload_end: end,
+            vlan_id: None,
+            outer_vlan_id: None,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(options: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&40000u16.to_be_bytes());
+        data.extend_from_slice(&3306u16.to_be_bytes());
+        data.extend_from_slice(&1000u32.to_be_bytes());
+        data.extend_from_slice(&2000u32.to_be_bytes());
+        data.push((((20 + options.len()) / 4) as u8) << 4);
+        data.push(TCP_ACK);
+        data.extend_from_slice(&[0xff, 0xff, 0, 0, 0, 0]);
+        data.extend_from_slice(options);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn ipv4(options: &[u8], flags_fragment: u16, protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let header_len = 20 + options.len();
+        let mut data = vec![0x40 | (header_len / 4) as u8, 0];
+        data.extend_from_slice(&((header_len + payload.len()) as u16).to_be_bytes());
+        data.extend_from_slice(&0x1234u16.to_be_bytes());
+        data.extend_from_slice(&flags_fragment.to_be_bytes());
+        data.extend_from_slice(&[64, protocol, 0, 0]);
+        data.extend_from_slice(&[10, 0, 0, 2, 10, 0, 0, 1]);
+        data.extend_from_slice(options);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn decode(data: &[u8]) -> TcpIpHeader {
+        TcpIpHeader::new(data, LinkType::Raw).unwrap().unwrap()
+    }
+
+    fn decode_ethernet(data: &[u8]) -> TcpIpHeader {
+        TcpIpHeader::new(data, LinkType::Ethernet).unwrap().unwrap()
+    }
+
+    ///
+    /// ipv6头部, extensions为扩展头部及其next header, 最后一个扩展头部之后为tcp
+    fn ipv6(extensions: &[(u8, Vec<u8>)], payload: &[u8], payload_len: Option<u16>) -> Vec<u8> {
+        let mut body = vec![];
+        for (i, (_, header)) in extensions.iter().enumerate() {
+            let mut header = header.clone();
+            header[0] = extensions.get(i + 1).map(|e| e.0).unwrap_or(IP_PROTOCOL_TCP);
+            body.extend_from_slice(&header);
+        }
+        body.extend_from_slice(payload);
+        let mut data = vec![0x60, 0, 0, 0];
+        data.extend_from_slice(&payload_len.unwrap_or(body.len() as u16).to_be_bytes());
+        data.push(extensions.first().map(|e| e.0).unwrap_or(IP_PROTOCOL_TCP));
+        data.push(64);
+        data.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend_from_slice(&body);
+        data
+    }
+
+    fn ipv6_payload(data: &[u8]) -> Option<Vec<u8>> {
+        TcpIpHeader::new(data, LinkType::Raw).unwrap().map(|h| data[h.payload_start..h.payload_end].to_vec())
+    }
+
+    #[test]
+    fn ipv4_tcp_without_options() {
+        let data = ipv4(&[], 0x4000, IP_PROTOCOL_TCP, &tcp(&[], b"\x01\x00\x00\x00\x0e"));
+        let header = decode(&data);
+        assert_eq!(header.source, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(header.destination, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!((header.source_port, header.destination_port), (40000, 3306));
+        assert_eq!((header.seq, header.ack, header.packet_flag), (1000, 2000, TCP_ACK));
+        assert_eq!(header.ip_id, Some(0x1234));
+        assert_eq!(&data[header.payload_start..header.payload_end], b"\x01\x00\x00\x00\x0e");
+    }
+
+    #[test]
+    fn ipv4_options_and_tcp_sack() {
+        // ip头部带4字节选项(router alert), tcp带timestamp及SACK选项
+        let tcp_options = [1, 1, 8, 10, 0, 0, 0, 1, 0, 0, 0, 2, 1, 1, 5, 10, 0, 0, 0x10, 0, 0, 0, 0x20, 0];
+        let data = ipv4(&[0x94, 0x04, 0, 0], 0, IP_PROTOCOL_TCP, &tcp(&tcp_options, b"select 1"));
+        let header = decode(&data);
+        assert_eq!(header.payload_start, 24 + 20 + tcp_options.len());
+        assert_eq!(&data[header.payload_start..header.payload_end], b"select 1");
+    }
+
+    #[test]
+    fn ipv4_trailing_padding_is_removed() {
+        let mut data = ipv4(&[], 0, IP_PROTOCOL_TCP, &tcp(&[], b""));
+        data.extend_from_slice(&[0; 6]);
+        let header = decode(&data);
+        assert_eq!(header.payload_start, header.payload_end);
+    }
+
+    #[test]
+    fn ipv4_fragments_and_other_protocols_are_skipped() {
+        let segment = tcp(&[], b"select 1");
+        // MF标记
+        assert!(TcpIpHeader::new(&ipv4(&[], 0x2000, IP_PROTOCOL_TCP, &segment), LinkType::Raw).unwrap().is_none());
+        // fragment offset不为0
+        assert!(TcpIpHeader::new(&ipv4(&[], 0x0010, IP_PROTOCOL_TCP, &segment), LinkType::Raw).unwrap().is_none());
+        // udp
+        assert!(TcpIpHeader::new(&ipv4(&[], 0, 17, &segment), LinkType::Raw).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_ipv4_and_tcp_headers() {
+        let data = ipv4(&[], 0, IP_PROTOCOL_TCP, &tcp(&[], b""));
+        assert!(matches!(TcpIpHeader::new(&data[..19], LinkType::Raw), Err(DecodeError::Truncated(_))));
+        // ip头部完整但tcp头部不足20字节
+        assert!(matches!(TcpIpHeader::new(&data[..30], LinkType::Raw), Err(DecodeError::Truncated(_))));
+        // IHL小于5
+        let mut bad = data.clone();
+        bad[0] = 0x44;
+        assert!(matches!(TcpIpHeader::new(&bad, LinkType::Raw), Err(DecodeError::BadIpHeader(_))));
+        // IHL超过实际长度
+        let mut bad = data.clone();
+        bad[0] = 0x4f;
+        assert!(matches!(TcpIpHeader::new(&bad[..40], LinkType::Raw), Err(DecodeError::Truncated(_))));
+        // total length小于头部长度
+        let mut bad = data.clone();
+        bad[2..4].copy_from_slice(&10u16.to_be_bytes());
+        assert!(matches!(TcpIpHeader::new(&bad, LinkType::Raw), Err(DecodeError::BadIpHeader(_))));
+        // tcp data offset小于5
+        let mut bad = data.clone();
+        bad[32] = 0x40;
+        assert!(matches!(TcpIpHeader::new(&bad, LinkType::Raw), Err(DecodeError::BadTcpHeader(_))));
+        // tcp选项超过实际长度
+        let mut bad = data.clone();
+        bad[32] = 0xf0;
+        assert!(matches!(TcpIpHeader::new(&bad, LinkType::Raw), Err(DecodeError::Truncated(_))));
+    }
+
+    #[test]
+    fn ipv6_tcp() {
+        let data = ipv6(&[], &tcp(&[], b"select 1"), None);
+        let header = decode(&data);
+        assert_eq!(header.source, "2001:db8::2".parse::<IpAddr>().unwrap());
+        assert_eq!(header.destination, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(header.ip_id, None);
+        assert_eq!(&data[header.payload_start..header.payload_end], b"select 1");
+    }
+
+    #[test]
+    fn ipv6_extension_headers() {
+        let segment = tcp(&[], b"select 1");
+        // hop-by-hop, 长度以8字节为单位且不包括前8字节
+        let hop_by_hop = (IPV6_HOP_BY_HOP, vec![0, 1, 1, 4, 0, 0, 0, 0, 1, 6, 0, 0, 0, 0, 0, 0]);
+        let routing = (IPV6_ROUTING, vec![0, 0, 0, 0, 0, 0, 0, 0]);
+        let destination = (IPV6_DESTINATION, vec![0, 0, 1, 4, 0, 0, 0, 0]);
+        // AH的长度以4字节为单位且不包括前8字节
+        let mut ah = vec![0, 4];
+        ah.extend_from_slice(&[0; 22]);
+        let ah = (IPV6_AH, ah);
+        let fragment = (IPV6_FRAGMENT, vec![0, 0, 0, 0, 0, 0, 0, 1]);
+        for extensions in &[vec![hop_by_hop.clone()], vec![routing.clone()], vec![destination.clone()], vec![ah.clone()],
+                            vec![fragment.clone()], vec![hop_by_hop, routing, fragment, ah, destination]] {
+            let data = ipv6(extensions, &segment, None);
+            assert_eq!(ipv6_payload(&data).as_deref(), Some(&b"select 1"[..]), "{:?}", extensions);
+        }
+    }
+
+    #[test]
+    fn ipv6_fragments_are_skipped() {
+        let segment = tcp(&[], b"select 1");
+        // M标记
+        let data = ipv6(&[(IPV6_FRAGMENT, vec![0, 0, 0, 1, 0, 0, 0, 1])], &segment, None);
+        assert_eq!(ipv6_payload(&data), None);
+        // fragment offset不为0
+        let data = ipv6(&[(IPV6_FRAGMENT, vec![0, 0, 0, 8, 0, 0, 0, 1])], &segment, None);
+        assert_eq!(ipv6_payload(&data), None);
+        // 只有保留位时为完整的包
+        let data = ipv6(&[(IPV6_FRAGMENT, vec![0, 0, 0, 6, 0, 0, 0, 1])], &segment, None);
+        assert_eq!(ipv6_payload(&data).as_deref(), Some(&b"select 1"[..]));
+    }
+
+    #[test]
+    fn ipv6_jumbo_payload_and_unknown_headers() {
+        // payload length为0时以抓包长度为准
+        let data = ipv6(&[], &tcp(&[], b"select 1"), Some(0));
+        assert_eq!(ipv6_payload(&data).as_deref(), Some(&b"select 1"[..]));
+        // ESP
+        let data = ipv6(&[(50, vec![0; 8])], &tcp(&[], b"select 1"), None);
+        assert_eq!(ipv6_payload(&data), None);
+        // payload length之后的填充字节
+        let mut data = ipv6(&[], &tcp(&[], b"select 1"), None);
+        data.extend_from_slice(&[0; 4]);
+        assert_eq!(ipv6_payload(&data).as_deref(), Some(&b"select 1"[..]));
+    }
+
+    #[test]
+    fn malformed_ipv6_headers() {
+        let data = ipv6(&[], &tcp(&[], b""), None);
+        assert!(matches!(TcpIpHeader::new(&data[..39], LinkType::Raw), Err(DecodeError::Truncated(_))));
+        // 扩展头部的长度超过包长度
+        let data = ipv6(&[(IPV6_HOP_BY_HOP, vec![0, 8, 1, 4, 0, 0, 0, 0])], &tcp(&[], b""), None);
+        assert!(matches!(TcpIpHeader::new(&data, LinkType::Raw), Err(DecodeError::Truncated(_))));
+        let data = ipv6(&[(IPV6_AH, vec![0, 0, 0, 0])], &[], None);
+        assert!(matches!(TcpIpHeader::new(&data, LinkType::Raw), Err(DecodeError::Truncated(_))));
+        let data = ipv6(&[(IPV6_FRAGMENT, vec![0, 0, 0, 0])], &[], None);
+        assert!(matches!(TcpIpHeader::new(&data, LinkType::Raw), Err(DecodeError::Truncated(_))));
+        // ethernet类型为ipv6但版本号不是6
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        frame.extend_from_slice(&ipv4(&[], 0, IP_PROTOCOL_TCP, &tcp(&[], b"")));
+        frame.extend_from_slice(&[0; 20]);
+        assert!(matches!(TcpIpHeader::new(&frame, LinkType::Ethernet), Err(DecodeError::BadIpHeader(_))));
+    }
+
+    #[test]
+    fn link_types() {
+        let ip4 = ipv4(&[], 0, IP_PROTOCOL_TCP, &tcp(&[], b"select 1"));
+        let ip6 = ipv6(&[], &tcp(&[], b"select 1"), None);
+        let mut ethernet = vec![0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x08, 0x00];
+        ethernet.extend_from_slice(&ip4);
+        let mut null_le = 2u32.to_le_bytes().to_vec();
+        null_le.extend_from_slice(&ip4);
+        // 大端机器上写入的DLT_NULL文件
+        let mut null_be = 2u32.to_be_bytes().to_vec();
+        null_be.extend_from_slice(&ip4);
+        let mut null_v6 = 30u32.to_le_bytes().to_vec();
+        null_v6.extend_from_slice(&ip6);
+        let mut null_v6_be = 24u32.to_be_bytes().to_vec();
+        null_v6_be.extend_from_slice(&ip6);
+        let mut loopback = 10u32.to_be_bytes().to_vec();
+        loopback.extend_from_slice(&ip6);
+        let mut sll = vec![0, 0, 0, 1, 0, 6, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0, 0, 0x86, 0xdd];
+        sll.extend_from_slice(&ip6);
+        let mut sll2 = vec![0x08, 0x00, 0, 0, 0, 0, 0, 2, 0, 1, 0, 6, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0, 0];
+        sll2.extend_from_slice(&ip4);
+        for (link_type, frame) in &[(LinkType::Ethernet, ethernet), (LinkType::Null, null_le), (LinkType::Null, null_be),
+                                    (LinkType::Null, null_v6), (LinkType::Null, null_v6_be), (LinkType::Loop, loopback),
+                                    (LinkType::Raw, ip4.clone()), (LinkType::Raw, ip6.clone()),
+                                    (LinkType::LinuxSll, sll), (LinkType::LinuxSll2, sll2)] {
+            let header = TcpIpHeader::new(frame, *link_type).unwrap().unwrap();
+            assert_eq!(&frame[header.payload_start..header.payload_end], b"select 1", "{:?}", link_type);
+        }
+    }
+
+    #[test]
+    fn link_types_without_ip() {
+        // arp
+        let mut ethernet = vec![0; 12];
+        ethernet.extend_from_slice(&[0x08, 0x06]);
+        ethernet.extend_from_slice(&[0; 28]);
+        assert!(TcpIpHeader::new(&ethernet, LinkType::Ethernet).unwrap().is_none());
+        // 未知的协议族
+        let mut null = 7u32.to_le_bytes().to_vec();
+        null.extend_from_slice(&[0; 40]);
+        assert!(TcpIpHeader::new(&null, LinkType::Null).unwrap().is_none());
+        assert!(matches!(TcpIpHeader::new(&[0x50; 40], LinkType::Raw), Err(DecodeError::BadIpHeader(_))));
+        for link_type in &[LinkType::Ethernet, LinkType::Null, LinkType::Loop, LinkType::Raw, LinkType::LinuxSll, LinkType::LinuxSll2] {
+            assert!(matches!(TcpIpHeader::new(&[], *link_type), Err(DecodeError::Truncated(_))), "{:?}", link_type);
+        }
+    }
+
+    #[test]
+    fn link_type_from_dlt() {
+        assert_eq!(LinkType::from_dlt(1), Some(LinkType::Ethernet));
+        assert_eq!(LinkType::from_dlt(0), Some(LinkType::Null));
+        assert_eq!(LinkType::from_dlt(108), Some(LinkType::Loop));
+        for dlt in &[12, 14, 101, 228, 229] {
+            assert_eq!(LinkType::from_dlt(*dlt), Some(LinkType::Raw));
+        }
+        assert_eq!(LinkType::from_dlt(113), Some(LinkType::LinuxSll));
+        assert_eq!(LinkType::from_dlt(276), Some(LinkType::LinuxSll2));
+        assert_eq!(LinkType::from_dlt(105), None);
+    }
+
+    fn vlan_frame(tags: &[(u16, u16)], payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb];
+        for (tpid, tci) in tags {
+            frame.extend_from_slice(&tpid.to_be_bytes());
+            frame.extend_from_slice(&tci.to_be_bytes());
+        }
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn vlan_tags() {
+        let ip4 = ipv4(&[], 0, IP_PROTOCOL_TCP, &tcp(&[], b"select 1"));
+        let header = decode_ethernet(&vlan_frame(&[], &ip4));
+        assert_eq!((header.vlan_id, header.outer_vlan_id), (None, None));
+        // 802.1Q, tci的高4位为优先级及DEI
+        let frame = vlan_frame(&[(ETHERTYPE_VLAN, 0xa064)], &ip4);
+        let header = decode_ethernet(&frame);
+        assert_eq!((header.vlan_id, header.outer_vlan_id), (Some(100), None));
+        assert_eq!(&frame[header.payload_start..header.payload_end], b"select 1");
+        // QinQ, vlan_id为内层, outer_vlan_id为外层
+        for outer_tpid in &[ETHERTYPE_QINQ, ETHERTYPE_QINQ_OLD, ETHERTYPE_VLAN] {
+            let frame = vlan_frame(&[(*outer_tpid, 200), (ETHERTYPE_VLAN, 100)], &ip4);
+            let header = decode_ethernet(&frame);
+            assert_eq!((header.vlan_id, header.outer_vlan_id), (Some(100), Some(200)));
+            assert_eq!(&frame[header.payload_start..header.payload_end], b"select 1");
+        }
+        // 三层tag时外层为最外层的tag
+        let header = decode_ethernet(&vlan_frame(&[(ETHERTYPE_QINQ, 300), (ETHERTYPE_QINQ, 200), (ETHERTYPE_VLAN, 100)], &ip4));
+        assert_eq!((header.vlan_id, header.outer_vlan_id), (Some(100), Some(300)));
+    }
+
+    #[test]
+    fn truncated_vlan_tag() {
+        let frame = vlan_frame(&[(ETHERTYPE_QINQ, 200), (ETHERTYPE_VLAN, 100)], &[]);
+        assert!(matches!(TcpIpHeader::new(&frame[..20], LinkType::Ethernet), Err(DecodeError::Truncated(_))));
+        // sll上的vlan tag
+        let mut sll = vec![0, 0, 0, 1, 0, 6, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0, 0, 0x81, 0x00, 0x00, 0x64];
+        assert!(matches!(TcpIpHeader::new(&sll, LinkType::LinuxSll), Err(DecodeError::Truncated(_))));
+        sll.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        sll.extend_from_slice(&ipv4(&[], 0, IP_PROTOCOL_TCP, &tcp(&[], b"")));
+        assert_eq!(TcpIpHeader::new(&sll, LinkType::LinuxSll).unwrap().unwrap().vlan_id, Some(100));
+    }
+}
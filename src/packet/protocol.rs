@@ -3,12 +3,553 @@
 @datetime: 2020/3/28
 */
 use std::error::Error;
-use std::io::{Read, Seek};
+use std::io::{Cursor, Read, Seek};
 use std::io;
+use std::sync::Arc;
 use std;
 use byteorder::{ReadBytesExt, LittleEndian};
 use crate::packet::{MysqlProtocol, StreamType, MysqlProtocolHeader, StreamPacket};
-use crate::session::{SessionHostInfo, SessionInfo};
+use crate::session::{SessionInfo, ResultState};
+
+const SERVER_MORE_RESULTS_EXISTS: u16 = 0x0008;
+const SERVER_SESSION_STATE_CHANGED: u16 = 0x4000;
+const SESSION_TRACK_SYSTEM_VARIABLES: u8 = 0x00;
+const SESSION_TRACK_SCHEMA: u8 = 0x01;
+const SESSION_TRACK_STATE_CHANGE: u8 = 0x02;
+const SESSION_TRACK_GTIDS: u8 = 0x03;
+const SESSION_TRACK_TRANSACTION_CHARACTERISTICS: u8 = 0x04;
+const SESSION_TRACK_TRANSACTION_STATE: u8 = 0x05;
+pub const MAX_PAYLOAD_LEN: usize = 0xffffff;
+const COM_QUERY: u8 = 0x03;
+const CLIENT_CONNECT_WITH_DB: u32 = 0x0000_0008;
+const CLIENT_PROTOCOL_41: u32 = 0x0000_0200;
+const CLIENT_SSL: u32 = 0x0000_0800;
+const CLIENT_SECURE_CONNECTION: u32 = 0x0000_8000;
+const CLIENT_PLUGIN_AUTH: u32 = 0x0008_0000;
+const CLIENT_CONNECT_ATTRS: u32 = 0x0010_0000;
+const CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA: u32 = 0x0020_0000;
+
+///
+/// 服务端发送的HandshakeV10包, 每个连接只有一个, 在该连接的所有审计记录中输出
+/// thread_id即服务端的连接id, 与performance_schema.threads.PROCESSLIST_ID及CONNECTION_ID()相同
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerHandshake{
+    pub protocol_version: u8,
+    pub server_version: String,
+    pub thread_id: u32,
+    pub capability_flags: u32,
+    pub character_set: u8,
+    pub status_flags: u16,
+    pub auth_plugin_name: String,
+}
+
+impl ServerHandshake{
+    ///
+    /// 从协议版本之后开始解析, 较早版本的服务端没有capability高位之后的部分
+    pub fn parse(protocol_version: u8, cur: &mut Cursor<Vec<u8>>) -> Result<ServerHandshake, Box<dyn Error>>{
+        /*
+        Type	        Name	                        Description
+        int<1>	        protocol version	            Always 10
+        string<NUL>	    server version	                human readable status information
+        int<4>	        thread id	                    a.k.a. connection id
+        string[8]	    auth-plugin-data-part-1	        first 8 bytes of the plugin provided data (scramble)
+        int<1>	        filler	                        0x00 byte, terminating the first part of a scramble
+        int<2>	        capability_flags_1	            The lower 2 bytes of the Capabilities Flags
+        int<1>	        character_set	                default server a_protocol_character_set, only the lower 8-bits
+        int<2>	        status_flags	                SERVER_STATUS_flags_enum
+        int<2>	        capability_flags_2	            The upper 2 bytes of the Capabilities Flags
+        int<1>	        auth_plugin_data_len	        length of the combined auth_plugin_data (scramble), if auth_plugin_data_len is > 0
+        string[10]	    reserved	                    reserved. All 0s.
+        $length	        auth-plugin-data-part-2	        Rest of the plugin provided data (scramble), $len=MAX(13, length of auth-plugin-data - 8)
+        NULL	        auth_plugin_name	            name of the auth_method that the auth_plugin_data belongs to
+
+        see: https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_connection_phase_packets_protocol_handshake_v10.html
+        */
+        let mut handshake = ServerHandshake{ protocol_version, ..Default::default() };
+        handshake.server_version = read_nul_string(cur)?;
+        handshake.thread_id = cur.read_u32::<LittleEndian>()?;
+        cur.seek(io::SeekFrom::Current(9))?;
+        handshake.capability_flags = cur.read_u16::<LittleEndian>()? as u32;
+        if cur.position() >= cur.get_ref().len() as u64 {
+            return Ok(handshake);
+        }
+        handshake.character_set = cur.read_u8()?;
+        handshake.status_flags = cur.read_u16::<LittleEndian>()?;
+        handshake.capability_flags |= (cur.read_u16::<LittleEndian>()? as u32) << 16;
+        let auth_plugin_data_len = cur.read_u8()? as i64;
+        cur.seek(io::SeekFrom::Current(10))?;
+        if handshake.capability_flags & CLIENT_SECURE_CONNECTION != 0 {
+            cur.seek(io::SeekFrom::Current((auth_plugin_data_len - 8).max(13)))?;
+        }
+        if handshake.capability_flags & CLIENT_PLUGIN_AUTH != 0 {
+            handshake.auth_plugin_name = read_nul_string(cur)?;
+        }
+        Ok(handshake)
+    }
+}
+
+///
+/// 客户端回复的HandshakeResponse包, 包含登录用户、初始库及连接属性
+/// 连接属性为客户端上报的key/value, 如_client_name、_client_version、program_name
+/// 使用ssl时客户端先发送只有32字节的SSLRequest, 之后的内容已加密, 只能获取到capability等信息
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HandshakeResponse{
+    pub capability_flags: u32,
+    pub max_packet_size: u32,
+    pub character_set: u8,
+    pub user_name: String,
+    pub auth_response_len: usize,               // 验证数据的长度, 验证数据本身不保留
+    pub database: String,
+    pub auth_plugin_name: String,
+    pub attributes: Vec<(String, String)>,      // 连接属性, 保持客户端发送的顺序
+    pub ssl: bool,                              // SSLRequest, 后续内容已加密
+}
+
+impl HandshakeResponse{
+    ///
+    /// 从包头之后开始解析, 根据客户端的capability判断各部分是否存在
+    pub fn parse(cur: &mut Cursor<Vec<u8>>) -> Result<HandshakeResponse, Box<dyn Error>>{
+        /*
+        Protocol::HandshakeResponse41:
+
+        Type	        Name	                        Description
+        int<4>	        client_flag	                    Capabilities Flags, CLIENT_PROTOCOL_41 always set.
+        int<4>	        max_packet_size	                maximum packet size
+        int<1>	        character_set	                client charset a_protocol_character_set, only the lower 8-bits
+        string[23]	    filler	                        filler to the size of the handhshake response packet. All 0s.
+        string<NUL>	    username	                    login user name
+        if capabilities & CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA {
+        string<length>	auth_response	                opaque authentication response data generated by Authentication Method
+        } else {
+        int<1>	        auth_response_length	        length of auth_response
+        string<length>	auth_response	                opaque authentication response data generated by Authentication Method
+        }
+        if capabilities & CLIENT_CONNECT_WITH_DB {
+        string<NUL>	    database	                    initial database for the connection
+        }
+        if capabilities & CLIENT_PLUGIN_AUTH {
+        string<NUL>	    client_plugin_name	            the Authentication Method used by the client to generate auth-response value in this packet
+        }
+        if capabilities & CLIENT_CONNECT_ATTRS {
+        int<lenenc>	    length of all key-values	    number of bytes in the key-value section
+        string<lenenc>	key1	                        Name of the 1st client attribute
+        string<lenenc>	value1	                        Value of the 1st client attribute
+        .. (if more data in length of all key-values, more keys and values parts)
+        }
+
+        Protocol::HandshakeResponse320:
+
+        Type	        Name	                        Description
+        int<2>	        client_flag	                    Capabilities Flags, only the lower 16 bits
+        int<3>	        max_packet_size	                maximum packet size, 0xFFFFFF max
+        string<NUL>	    username	                    login user name
+        if capabilities & CLIENT_CONNECT_WITH_DB {
+        string<NUL>	    auth-response	                Opaque authentication response data generated by Authentication Method
+        string<NUL>	    database	                    initial database for the connection
+        } else {
+        string<EOF>	    auth-response	                Opaque authentication response data generated by Authentication Method
+        }
+
+        see: https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_connection_phase_packets_protocol_handshake_response.html
+        */
+        let mut response = HandshakeResponse::default();
+        let capability_flags = cur.read_u16::<LittleEndian>()? as u32;
+        if capability_flags & CLIENT_PROTOCOL_41 == 0 {
+            response.capability_flags = capability_flags;
+            response.max_packet_size = cur.read_u24::<LittleEndian>()?;
+            response.user_name = read_nul_string(cur)?;
+            if capability_flags & CLIENT_CONNECT_WITH_DB != 0 {
+                response.auth_response_len = read_nul_string(cur)?.len();
+                response.database = read_nul_string(cur)?;
+            } else {
+                response.auth_response_len = cur.get_ref().len().saturating_sub(cur.position() as usize);
+            }
+            return Ok(response);
+        }
+        response.capability_flags = capability_flags | (cur.read_u16::<LittleEndian>()? as u32) << 16;
+        response.max_packet_size = cur.read_u32::<LittleEndian>()?;
+        response.character_set = cur.read_u8()?;
+        cur.seek(io::SeekFrom::Current(23))?;
+        if cur.position() >= cur.get_ref().len() as u64 {
+            response.ssl = response.capability_flags & CLIENT_SSL != 0;
+            return Ok(response);
+        }
+        response.user_name = read_nul_string(cur)?;
+        response.auth_response_len = if response.capability_flags & CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA != 0 {
+            read_lenenc_bytes(cur)?.len()
+        } else if response.capability_flags & CLIENT_SECURE_CONNECTION != 0 {
+            let len = cur.read_u8()? as usize;
+            read_bytes(cur, len)?.len()
+        } else {
+            read_nul_string(cur)?.len()
+        };
+        if response.capability_flags & CLIENT_CONNECT_WITH_DB != 0 {
+            response.database = read_nul_string(cur)?;
+        }
+        if response.capability_flags & CLIENT_PLUGIN_AUTH != 0 {
+            response.auth_plugin_name = read_nul_string(cur)?;
+        }
+        if response.capability_flags & CLIENT_CONNECT_ATTRS != 0 && cur.position() < cur.get_ref().len() as u64 {
+            // 包被截断时保留已解析的连接属性
+            let len = read_lenenc_int(cur)?;
+            let end = cur.position() + len;
+            while cur.position() < end {
+                match (read_lenenc_string(cur), read_lenenc_string(cur)) {
+                    (Ok(key), Ok(value)) => response.attributes.push((key, value)),
+                    _ => break
+                }
+            }
+        }
+        Ok(response)
+    }
+}
+
+///
+/// 服务端返回的OK包, 包括CLIENT_SESSION_TRACK开启时的session状态变化
+/// 存在多个结果集(多语句、存储过程)时每个结果集有一个OK包, 通过merge合并到同一个请求中
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OkPacket{
+    pub affected_rows: u64,
+    pub last_insert_id: u64,
+    pub status_flags: u16,
+    pub warnings: u16,
+    pub info: String,                                   // 如Rows matched: 1  Changed: 1  Warnings: 0
+    pub system_variables: Vec<(String, String)>,        // 变化的系统变量, session_track_system_variables
+    pub schema: Option<String>,                         // 切换后的库
+    pub state_changed: bool,                            // session状态发生了变化, session_track_state_change
+    pub gtids: Option<String>,                          // 该请求产生的gtid, session_track_gtids
+    pub transaction_characteristics: Option<String>,    // 重新执行当前事务特性的语句, session_track_transaction_info=CHARACTERISTICS
+    pub transaction_state: Option<String>,              // 事务状态, 8个字符, session_track_transaction_info
+}
+
+impl OkPacket{
+    ///
+    /// 从包头(0x00/0xfe)之后开始解析
+    /// 不知道客户端是否开启了CLIENT_SESSION_TRACK, 带有SERVER_SESSION_STATE_CHANGED或剩余部分正好为一个length encoded string时按开启处理
+    pub fn parse(cur: &mut Cursor<Vec<u8>>) -> Result<OkPacket, Box<dyn Error>>{
+        /*
+        Type	        Name	            Description
+        int<1>	        header	            0x00 or 0xFE the OK packet header
+        int<lenenc>	    affected_rows	    affected rows
+        int<lenenc>	    last_insert_id	    last insert-id
+        if capabilities & CLIENT_PROTOCOL_41 {
+        int<2>	        status_flags	    SERVER_STATUS_flags_enum
+        int<2>	        warnings	        number of warnings
+        }
+        if capabilities & CLIENT_SESSION_TRACK {
+        string<lenenc>	info	            human readable status information
+        if status_flags & SERVER_SESSION_STATE_CHANGED {
+        string<lenenc>	session state info	Session State Information
+        }
+        } else {
+        string<EOF>	    info	            human readable status information
+        }
+
+        Session State Information, 每一项为:
+        int<1>	        type	            enum_session_state_type
+        string<lenenc>	data	            data of the changed session info
+
+        type                                        data
+        SESSION_TRACK_SYSTEM_VARIABLES              string<lenenc> name, string<lenenc> value
+        SESSION_TRACK_SCHEMA                        string<lenenc> name
+        SESSION_TRACK_STATE_CHANGE                  string<lenenc> is_tracked, "1"
+        SESSION_TRACK_GTIDS                         int<1> encoding specification(0), string<lenenc> gtids
+        SESSION_TRACK_TRANSACTION_CHARACTERISTICS   string<lenenc> characteristics
+        SESSION_TRACK_TRANSACTION_STATE             string<lenenc> state
+
+        see: https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_basic_ok_packet.html
+        */
+        let mut ok_packet = OkPacket{
+            affected_rows: read_lenenc_int(cur)?,
+            last_insert_id: read_lenenc_int(cur)?,
+            status_flags: cur.read_u16::<LittleEndian>()?,
+            ..Default::default()
+        };
+        if cur.position() >= cur.get_ref().len() as u64 {
+            return Ok(ok_packet);
+        }
+        ok_packet.warnings = cur.read_u16::<LittleEndian>()?;
+        let state_changed = ok_packet.status_flags & SERVER_SESSION_STATE_CHANGED != 0;
+        if !state_changed && !remaining_is_lenenc_string(cur) {
+            let mut tmp: Vec<u8> = vec![];
+            cur.read_to_end(tmp.as_mut())?;
+            ok_packet.info = String::from_utf8_lossy(&tmp).to_string();
+            return Ok(ok_packet);
+        }
+        ok_packet.info = read_lenenc_string(cur)?;
+        if state_changed {
+            let mut state = Cursor::new(read_lenenc_bytes(cur)?);
+            while (state.position() as usize) < state.get_ref().len() {
+                let track_type = state.read_u8()?;
+                let data = read_lenenc_bytes(&mut state)?;
+                ok_packet.session_track(track_type, &mut Cursor::new(data))?;
+            }
+        }
+        Ok(ok_packet)
+    }
+
+    ///
+    /// 解析一项session状态变化, 未知的类型忽略
+    fn session_track(&mut self, track_type: u8, data: &mut Cursor<Vec<u8>>) -> Result<(), Box<dyn Error>>{
+        match track_type {
+            SESSION_TRACK_SYSTEM_VARIABLES => {
+                let name = read_lenenc_string(data)?;
+                let value = read_lenenc_string(data)?;
+                self.system_variables.push((name, value));
+            }
+            SESSION_TRACK_SCHEMA => self.schema = Some(read_lenenc_string(data)?),
+            SESSION_TRACK_STATE_CHANGE => self.state_changed = read_lenenc_string(data)? == "1",
+            SESSION_TRACK_GTIDS => {
+                let _encoding_specification = data.read_u8()?;
+                self.gtids = Some(read_lenenc_string(data)?);
+            }
+            SESSION_TRACK_TRANSACTION_CHARACTERISTICS => self.transaction_characteristics = Some(read_lenenc_string(data)?),
+            SESSION_TRACK_TRANSACTION_STATE => self.transaction_state = Some(read_lenenc_string(data)?),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    ///
+    /// 合并同一个请求的后续OK包, 影响行数及warning数累加, 其余取最近一次的值
+    pub fn merge(&mut self, other: OkPacket){
+        self.affected_rows += other.affected_rows;
+        if other.last_insert_id != 0 {
+            self.last_insert_id = other.last_insert_id;
+        }
+        self.status_flags = other.status_flags;
+        self.warnings = self.warnings.saturating_add(other.warnings);
+        if !other.info.is_empty() {
+            self.info = other.info;
+        }
+        self.system_variables.extend(other.system_variables);
+        self.schema = other.schema.or_else(|| self.schema.take());
+        self.state_changed |= other.state_changed;
+        self.gtids = other.gtids.or_else(|| self.gtids.take());
+        self.transaction_characteristics = other.transaction_characteristics.or_else(|| self.transaction_characteristics.take());
+        self.transaction_state = other.transaction_state.or_else(|| self.transaction_state.take());
+    }
+}
+
+///
+/// 剩余部分是否正好为一个length encoded string, 不移动读取位置
+fn remaining_is_lenenc_string(cur: &mut Cursor<Vec<u8>>) -> bool{
+    let start = cur.position();
+    let matched = match read_lenenc_int(cur) {
+        Ok(len) => cur.position().checked_add(len) == Some(cur.get_ref().len() as u64),
+        Err(_) => false
+    };
+    cur.set_position(start);
+    matched
+}
+
+///
+/// 读取以0结尾的字符串, 部分服务端的最后一个字符串没有结尾的0, 读取到包结尾为止
+pub fn read_nul_string(cur: &mut Cursor<Vec<u8>>) -> Result<String, Box<dyn Error>>{
+    let data = cur.get_ref();
+    let start = (cur.position() as usize).min(data.len());
+    let (value, next) = match data[start..].iter().position(|&b| b == 0) {
+        Some(n) => (&data[start..start + n], start + n + 1),
+        None => (&data[start..], data.len())
+    };
+    let value = String::from_utf8_lossy(value).to_string();
+    cur.set_position(next as u64);
+    Ok(value)
+}
+
+///
+/// 读取固定长度的数据, 长度超过包的剩余部分时返回错误
+pub fn read_bytes(cur: &mut Cursor<Vec<u8>>, len: usize) -> Result<Vec<u8>, Box<dyn Error>>{
+    let remaining = cur.get_ref().len().saturating_sub(cur.position() as usize);
+    if len > remaining {
+        return Err(format!("length {} exceeds remaining packet data {}", len, remaining).into());
+    }
+    let mut value = vec![0u8; len];
+    cur.read_exact(&mut value)?;
+    Ok(value)
+}
+
+///
+/// 读取length encoded string
+pub fn read_lenenc_bytes(cur: &mut Cursor<Vec<u8>>) -> Result<Vec<u8>, Box<dyn Error>>{
+    let len = read_lenenc_int(cur)?;
+    read_bytes(cur, len as usize)
+}
+
+pub fn read_lenenc_string(cur: &mut Cursor<Vec<u8>>) -> Result<String, Box<dyn Error>>{
+    Ok(String::from_utf8_lossy(&read_lenenc_bytes(cur)?).to_string())
+}
+
+///
+/// USE语句切换到的库, 不是USE语句时返回None
+/// 只跳过开头的空白及/* */注释, 库名可以用反引号括起来
+fn use_statement_database(sql: &str) -> Option<String>{
+    let mut sql = sql.trim_start();
+    while sql.starts_with("/*") {
+        sql = sql[sql.find("*/")? + 2..].trim_start();
+    }
+    // 前3个字节可能落在多字节字符中间, 不能直接切片
+    let rest = match sql.get(..3) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("use") => &sql[3..],
+        _ => return None
+    };
+    if !rest.starts_with(|c: char| c.is_whitespace() || c == '`') {
+        return None;
+    }
+    let name = rest.trim().trim_end_matches(';').trim_end();
+    let database = if let Some(quoted) = name.strip_prefix('`') {
+        quoted.strip_suffix('`')?.replace("``", "`")
+    } else {
+        name.to_string()
+    };
+    if database.is_empty() || (!name.starts_with('`') && database.contains(char::is_whitespace)) {
+        return None;
+    }
+    Some(database)
+}
+
+///
+/// 拼接后的mysql逻辑包, data包含第一个物理包的4字节包头
+/// 结果集的行数据及超过上限的sql文本只保留部分payload, len为实际的payload长度
+#[derive(Debug, Clone)]
+pub struct MysqlPacket{
+    pub data: Vec<u8>,
+    pub len: usize,                 // 所有物理包payload的总长度, 包括未保留的部分
+    pub truncated: bool,            // payload未全部保留
+}
+
+///
+/// 当前正在读取的物理包
+#[derive(Debug, Clone)]
+struct PhysicalPacket{
+    remaining: usize,               // 还未读取的payload字节数
+    last: bool,                     // payload小于0xffffff, 为逻辑包的最后一个物理包
+}
+
+///
+/// 将tcp重组后的连续数据切分为mysql逻辑包
+/// payload为0xffffff的物理包与后续的物理包拼接为一个逻辑包
+/// 结果集的行数据只需要首字节判断是否为结束包, 不复制首字节之后的payload
+/// COM_QUERY只保留命令字节及sql文本的前max_sql_len字节, 大批量写入的sql不会整个缓存
+#[derive(Debug, Default)]
+pub struct MysqlPacketAssembler{
+    pending: Vec<u8>,                       // 上一次剩余的不足一个物理包头的数据
+    current: Option<PhysicalPacket>,
+    logical: Option<MysqlPacket>,
+    keep: Option<usize>,                    // 当前逻辑包最多保留的payload字节数, None时全部保留
+}
+
+impl MysqlPacketAssembler{
+    pub fn new() -> MysqlPacketAssembler{
+        MysqlPacketAssembler::default()
+    }
+
+    ///
+    /// 数据流出现缺口时丢弃未完成的包
+    pub fn reset(&mut self){
+        self.pending.clear();
+        self.current = None;
+        self.logical = None;
+        self.keep = None;
+    }
+
+    ///
+    /// 加入按顺序重组的数据, 返回所有已完整的逻辑包
+    /// 只有包头被分段截断时才缓存数据, payload直接从data复制到逻辑包中
+    /// rows为true时数据流正在返回结果集的行数据, 直到ERR包或结束包之前的逻辑包都只保留首字节
+    /// max_query_len只用于请求方向, seq_id为0的COM_QUERY最多保留命令字节及max_query_len字节的sql文本
+    pub fn push(&mut self, data: &[u8], mut rows: bool, max_query_len: Option<usize>) -> Vec<MysqlPacket>{
+        let mut buf = std::mem::take(&mut self.pending);
+        let input: &[u8] = if buf.is_empty() {
+            data
+        } else {
+            buf.extend_from_slice(data);
+            &buf
+        };
+        let mut packets = vec![];
+        let mut start = 0;
+        loop {
+            if self.current.is_none() {
+                if input.len() < start + 4 {
+                    break;
+                }
+                let header = &input[start..start + 4];
+                let payload = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+                match self.logical.as_mut() {
+                    // 逻辑包的seq_id以最后一个物理包为准, 返回包的seq_id在此基础上递增
+                    Some(logical) => logical.data[3] = header[3],
+                    None => self.logical = Some(MysqlPacket{ data: header.to_vec(), len: 0, truncated: false })
+                }
+                self.current = Some(PhysicalPacket{ remaining: payload, last: payload < MAX_PAYLOAD_LEN });
+                start += 4;
+            }
+            let (remaining, last) = match &self.current {
+                Some(v) => (v.remaining, v.last),
+                None => break
+            };
+            let n = remaining.min(input.len() - start);
+            if let Some(logical) = self.logical.as_mut() {
+                if n > 0 && logical.len == 0 {
+                    // 逻辑包的首字节
+                    let code = input[start];
+                    if rows {
+                        // 行数据不会以0xff开头, 以0xfe开头时payload至少为0xffffff
+                        if code == 0xff || (code == 0xfe && last) {
+                            // 结果集已结束, 之后的包属于下一个返回
+                            rows = false;
+                        } else {
+                            self.keep = Some(1);
+                        }
+                    } else if let Some(max_query_len) = max_query_len {
+                        if logical.data[3] == 0 && code == COM_QUERY {
+                            self.keep = Some(max_query_len.saturating_add(1));
+                        }
+                    }
+                }
+                let kept = logical.data.len() - 4;
+                let take = match self.keep {
+                    Some(keep) => n.min(keep.saturating_sub(kept)),
+                    None => n
+                };
+                logical.data.extend_from_slice(&input[start..start + take]);
+                logical.len += n;
+            }
+            start += n;
+            if n < remaining {
+                self.current = Some(PhysicalPacket{ remaining: remaining - n, last });
+                break;
+            }
+            self.current = None;
+            if last {
+                self.keep = None;
+                if let Some(mut logical) = self.logical.take() {
+                    logical.truncated = logical.data.len() - 4 < logical.len;
+                    packets.push(logical);
+                }
+            }
+        }
+        self.pending = input[start..].to_vec();
+        packets
+    }
+}
+
+///
+/// 读取length encoded integer
+pub fn read_lenenc_int(cur: &mut Cursor<Vec<u8>>) -> Result<u64, Box<dyn Error>> {
+    let first = cur.read_u8()?;
+    lenenc_int(first, cur)
+}
+
+///
+/// 根据已读取的首字节继续读取length encoded integer
+pub fn lenenc_int(first: u8, cur: &mut Cursor<Vec<u8>>) -> Result<u64, Box<dyn Error>> {
+    match first {
+        0xfc => Ok(cur.read_u16::<LittleEndian>()? as u64),
+        0xfd => Ok(cur.read_u24::<LittleEndian>()? as u64),
+        0xfe => Ok(cur.read_u64::<LittleEndian>()?),
+        _ => Ok(first as u64)
+    }
+}
 
 impl MysqlProtocol{
     pub fn new(stream_packet: &mut StreamPacket) -> Result<MysqlProtocol, Box<dyn Error>>{
@@ -99,15 +640,15 @@ impl MysqlProtocol{
     pub fn protocol_unpacket(&self, stream_packet: &mut StreamPacket, session_info: &mut SessionInfo) -> std::result::Result<(), Box<dyn Error>> {
         match self{
             MysqlProtocol::OKPacket =>{
-                self.unpacket_ok_packet(session_info, stream_packet);
+                self.unpacket_ok_packet(session_info, stream_packet)?;
             } MysqlProtocol::ERRpacket => {
                 self.unpacket_err_packet(session_info, stream_packet)?;
             } MysqlProtocol::HandshakePacket => {
                 self.unpacket_handshake_packet(session_info, stream_packet);
             } MysqlProtocol::EOFPacket => {
-                self.unpacket_eof_packet(session_info, stream_packet);
+                self.unpacket_eof_packet(session_info, stream_packet)?;
             } MysqlProtocol::TextResult => {
-                self.unpacket_text_result(session_info, stream_packet);
+                self.unpacket_text_result(session_info, stream_packet)?;
             } MysqlProtocol::ComQuery => {
                 self.unpacket_com_query(session_info, stream_packet)?;
             } MysqlProtocol::ComInitDb => {
@@ -133,14 +674,17 @@ impl MysqlProtocol{
 
         Protocol::HandshakeV9:  0x09
         Protocol::HandshakeV10: 0x10
+
+        握手包解析失败时仍按连接建立流程处理, 只是没有服务端信息
         */
+        session_info.handshake = ServerHandshake::parse(0x0a, &mut stream_packet.data_cur).ok().map(Arc::new);
         session_info.server_response = MysqlProtocol::HandshakePacket;
         session_info.connection_pre = true;
         session_info.is_ok = true;
         session_info.end_time = stream_packet.ts.clone();
     }
 
-    fn unpacket_text_result(&self, session_info: &mut SessionInfo, stream_packet: &mut StreamPacket) {
+    fn unpacket_text_result(&self, session_info: &mut SessionInfo, stream_packet: &mut StreamPacket) -> std::result::Result<(), Box<dyn Error>> {
         /*
         A Text Resultset is a possible COM_QUERY Response.
 
@@ -153,13 +697,18 @@ impl MysqlProtocol{
 
         */
         if session_info.connection_pre{
-            return;
+            return Ok(());
         }
+        // 第一个包为列数量, 首字节在判断包类型时已读取
+        let code = stream_packet.data_cur.get_ref()[4];
+        let column_count = lenenc_int(code, &mut stream_packet.data_cur)?;
+        session_info.result_state = ResultState::ColumnDefinition(column_count);
         session_info.server_response = MysqlProtocol::TextResult;
         session_info.end_time = stream_packet.ts.clone();
+        Ok(())
     }
 
-    fn unpacket_eof_packet(&self, session_info: &mut SessionInfo, stream_packet: &mut StreamPacket){
+    fn unpacket_eof_packet(&self, session_info: &mut SessionInfo, stream_packet: &mut StreamPacket) -> std::result::Result<(), Box<dyn Error>> {
         /*
         If CLIENT_PROTOCOL_41 is enabled, the EOF packet contains a warning count and status flags.
 
@@ -169,11 +718,21 @@ impl MysqlProtocol{
 
         Type	Name	Description
         int<1>	header	0xFE EOF packet header
-        ...........................
+        if capabilities & CLIENT_PROTOCOL_41 {
+        int<2>	warnings	number of warnings
+        int<2>	status_flags	SERVER_STATUS_flags_enum
+        }
         */
+        session_info.more_results = false;
+        if stream_packet.protocol_header.payload >= 5 {
+            let _warnings = stream_packet.data_cur.read_u16::<LittleEndian>()?;
+            let status_flags = stream_packet.data_cur.read_u16::<LittleEndian>()?;
+            session_info.more_results = status_flags & SERVER_MORE_RESULTS_EXISTS != 0;
+        }
         session_info.server_response = MysqlProtocol::EOFPacket;
         session_info.end_time = stream_packet.ts.clone();
         session_info.connection_pre = false;
+        Ok(())
     }
 
     fn unpacket_err_packet(&self, session_info: &mut SessionInfo, stream_packet: &mut StreamPacket) -> std::result::Result<(), Box<dyn Error>> {
@@ -195,32 +754,54 @@ impl MysqlProtocol{
         */
         stream_packet.data_cur.seek(io::SeekFrom::Current(2))?;
         let mut tmp: Vec<u8> = vec![];
-        stream_packet.data_cur.read_exact(tmp.as_mut())?;
-        session_info.execute_sql = String::from_utf8_lossy(&tmp).to_string();
+        stream_packet.data_cur.read_to_end(tmp.as_mut())?;
+        session_info.response_value = String::from_utf8_lossy(&tmp).to_string();
+        session_info.result_state = ResultState::Null;
+        session_info.more_results = false;
         session_info.server_response = MysqlProtocol::ERRpacket;
         session_info.end_time = stream_packet.ts.clone();
         session_info.connection_pre = false;
         Ok(())
     }
 
-    fn unpacket_ok_packet(&self, session_info: &mut SessionInfo, stream_packet: &mut StreamPacket) {
+    fn unpacket_ok_packet(&self, session_info: &mut SessionInfo, stream_packet: &mut StreamPacket) -> std::result::Result<(), Box<dyn Error>> {
         /*
         An OK packet is sent from the server to the client to signal successful completion of a command.
 
         As of MySQL 5.7.5, OK packes are also used to indicate EOF, and EOF packets are deprecated
 
-        Type	        Name	            Description
-        int<1>	        header	            0x00 or 0xFE the OK packet header
-        int<lenenc>	    affected_rows	    affected rows
-        int<lenenc>	    last_insert_id	    last insert-id
-        ..................................................
-        see: https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_basic_ok_packet.html
+        info与ERR包的错误信息一样记录在response_value中, SESSION_TRACK_SCHEMA为切换后的库
 
-        :return:
+        COM_STMT_PREPARE成功时返回的COM_STMT_PREPARE_OK首字节同为0x00, 但不是OK包, 只记录执行成功:
+        int<1>	        status	            0x00: OK
+        int<4>	        statement_id	    statement ID
+        int<2>	        num_columns	        number of columns
+        int<2>	        num_params	        number of parameters
+        int<1>	        reserved_1	        [00] filler
+        int<2>	        warning_count	    number of warnings
         */
+        if let MysqlProtocol::ComStmtPrepare = session_info.client_request {
+            session_info.server_response = MysqlProtocol::OKPacket;
+            session_info.end_time = stream_packet.ts.clone();
+            session_info.connection_pre = false;
+            return Ok(());
+        }
+        let ok_packet = OkPacket::parse(&mut stream_packet.data_cur)?;
+        session_info.more_results = ok_packet.status_flags & SERVER_MORE_RESULTS_EXISTS != 0;
+        if let Some(schema) = &ok_packet.schema {
+            session_info.new_database = Some(schema.clone());
+        }
+        if !ok_packet.info.is_empty() {
+            session_info.response_value = ok_packet.info.clone();
+        }
+        match &mut session_info.ok_packet {
+            Some(v) => v.merge(ok_packet),
+            None => session_info.ok_packet = Some(ok_packet)
+        }
         session_info.server_response = MysqlProtocol::OKPacket;
         session_info.end_time = stream_packet.ts.clone();
         session_info.connection_pre = false;
+        Ok(())
     }
 
     pub fn unpacket_com_query(&self, session_info: &mut SessionInfo, stream_packet: &mut StreamPacket) -> std::result::Result<(), Box<dyn Error>> {
@@ -229,9 +810,18 @@ impl MysqlProtocol{
         int<1>	    command	0x03: COM_QUERY
         string<EOF>	query	the text of the SQL query to execute
         */
+        // 重组时已只保留sql文本的前max_sql_len字节, 截断处可能在多字节字符中间
         let mut tmp: Vec<u8> = vec![];
         stream_packet.data_cur.read_to_end(tmp.as_mut())?;
+        if tmp.len() > stream_packet.max_sql_len {
+            tmp.truncate(stream_packet.max_sql_len);
+            session_info.sql_truncated = true;
+        }
+        if stream_packet.data_truncated {
+            session_info.sql_truncated = true;
+        }
         session_info.execute_sql = String::from_utf8_lossy(&tmp).to_string();
+        session_info.new_database = use_statement_database(&session_info.execute_sql);
         session_info.client_request = MysqlProtocol::ComQuery;
         session_info.is_ok = true;
         Ok(())
@@ -248,8 +838,10 @@ impl MysqlProtocol{
             ERR_Packet on error
         */
         let mut tmp: Vec<u8> = vec![];
-        stream_packet.data_cur.read_exact(tmp.as_mut())?;
-        session_info.execute_sql = format!("use database {}",String::from_utf8_lossy(&tmp).to_string());
+        stream_packet.data_cur.read_to_end(tmp.as_mut())?;
+        let database = String::from_utf8_lossy(&tmp).to_string();
+        session_info.execute_sql = format!("use database {}", database);
+        session_info.new_database = Some(database);
         session_info.client_request = MysqlProtocol::ComInitDb;
         session_info.is_ok = true;
         Ok(())
@@ -318,3 +910,323 @@ impl MysqlProtocolHeader{
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lenenc(out: &mut Vec<u8>, data: &[u8]) {
+        if data.len() < 0xfb {
+            out.push(data.len() as u8);
+        } else {
+            out.push(0xfc);
+            out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        }
+        out.extend_from_slice(data);
+    }
+
+    fn response41(capability_flags: u32, tail: &[u8]) -> Vec<u8> {
+        let mut data = capability_flags.to_le_bytes().to_vec();
+        data.extend_from_slice(&16777216u32.to_le_bytes());
+        data.push(0x21);
+        data.extend_from_slice(&[0; 23]);
+        data.extend_from_slice(tail);
+        data
+    }
+
+    #[test]
+    fn handshake_response41() {
+        let capability_flags = CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_CONNECT_WITH_DB
+            | CLIENT_PLUGIN_AUTH | CLIENT_CONNECT_ATTRS;
+        let mut tail = b"root\0".to_vec();
+        tail.push(20);
+        tail.extend_from_slice(&[b'x'; 20]);
+        tail.extend_from_slice(b"test\0caching_sha2_password\0");
+        let mut attributes = vec![];
+        lenenc(&mut attributes, b"_client_name");
+        lenenc(&mut attributes, b"libmysql");
+        lenenc(&mut attributes, b"program_name");
+        lenenc(&mut attributes, b"mysql");
+        lenenc(&mut attributes, b"_connector_license");
+        lenenc(&mut attributes, &[b'x'; 300]);
+        // 属性部分的长度为3字节, 最后一项为空的key/value, 正好在属性部分的结尾处结束
+        lenenc(&mut attributes, b"");
+        lenenc(&mut attributes, b"");
+        lenenc(&mut tail, &attributes);
+        let response = HandshakeResponse::parse(&mut Cursor::new(response41(capability_flags, &tail))).unwrap();
+        assert_eq!(response, HandshakeResponse{
+            capability_flags,
+            max_packet_size: 16777216,
+            character_set: 0x21,
+            user_name: String::from("root"),
+            auth_response_len: 20,
+            database: String::from("test"),
+            auth_plugin_name: String::from("caching_sha2_password"),
+            attributes: vec![(String::from("_client_name"), String::from("libmysql")),
+                             (String::from("program_name"), String::from("mysql")),
+                             (String::from("_connector_license"), "x".repeat(300)),
+                             (String::new(), String::new())],
+            ssl: false,
+        });
+    }
+
+    #[test]
+    fn handshake_response41_with_lenenc_auth_data_and_truncated_attributes() {
+        let capability_flags = CLIENT_PROTOCOL_41 | CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA | CLIENT_CONNECT_ATTRS;
+        let mut tail = b"app\0".to_vec();
+        lenenc(&mut tail, &[b'x'; 32]);
+        tail.extend_from_slice(&[30, 3]);
+        tail.extend_from_slice(b"_os");
+        tail.extend_from_slice(&[5]);
+        tail.extend_from_slice(b"Lin");
+        let response = HandshakeResponse::parse(&mut Cursor::new(response41(capability_flags, &tail))).unwrap();
+        assert_eq!(response.user_name, "app");
+        assert_eq!(response.auth_response_len, 32);
+        assert!(response.database.is_empty());
+        assert!(response.attributes.is_empty());
+    }
+
+    #[test]
+    fn ssl_request() {
+        let capability_flags = CLIENT_PROTOCOL_41 | CLIENT_SSL | CLIENT_CONNECT_WITH_DB;
+        let response = HandshakeResponse::parse(&mut Cursor::new(response41(capability_flags, &[]))).unwrap();
+        assert!(response.ssl);
+        assert_eq!(response.capability_flags, capability_flags);
+        assert!(response.user_name.is_empty());
+    }
+
+    #[test]
+    fn handshake_response320() {
+        let mut data = (CLIENT_CONNECT_WITH_DB as u16).to_le_bytes().to_vec();
+        data.extend_from_slice(&0xffffffu32.to_le_bytes()[..3]);
+        data.extend_from_slice(b"root\0scramble\0test\0");
+        let response = HandshakeResponse::parse(&mut Cursor::new(data)).unwrap();
+        assert_eq!(response.capability_flags, CLIENT_CONNECT_WITH_DB);
+        assert_eq!(response.max_packet_size, 0xffffff);
+        assert_eq!(response.user_name, "root");
+        assert_eq!(response.auth_response_len, 8);
+        assert_eq!(response.database, "test");
+
+        let mut data = 0u16.to_le_bytes().to_vec();
+        data.extend_from_slice(&0xffffu32.to_le_bytes()[..3]);
+        data.extend_from_slice(b"root\0scramble");
+        let response = HandshakeResponse::parse(&mut Cursor::new(data)).unwrap();
+        assert_eq!(response.user_name, "root");
+        assert_eq!(response.auth_response_len, 8);
+        assert!(response.database.is_empty());
+    }
+
+    fn ok_packet(status_flags: u16, tail: &[u8]) -> Vec<u8> {
+        let mut data = vec![1, 5];
+        data.extend_from_slice(&status_flags.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+        data.extend_from_slice(tail);
+        data
+    }
+
+    #[test]
+    fn ok_packet_without_session_track() {
+        let data = ok_packet(0x0002, b"Rows matched: 1  Changed: 1  Warnings: 0");
+        let ok_packet = OkPacket::parse(&mut Cursor::new(data)).unwrap();
+        assert_eq!(ok_packet.affected_rows, 1);
+        assert_eq!(ok_packet.last_insert_id, 5);
+        assert_eq!(ok_packet.status_flags, 0x0002);
+        assert_eq!(ok_packet.info, "Rows matched: 1  Changed: 1  Warnings: 0");
+        assert!(ok_packet.system_variables.is_empty());
+
+        // 只有状态、没有warnings及之后部分的OK包
+        let ok_packet = OkPacket::parse(&mut Cursor::new(vec![0, 0, 2, 0])).unwrap();
+        assert_eq!(ok_packet, OkPacket{ status_flags: 0x0002, ..Default::default() });
+    }
+
+    #[test]
+    fn ok_packet_with_session_state() {
+        let mut state = vec![];
+        let mut item = vec![];
+        lenenc(&mut item, b"autocommit");
+        lenenc(&mut item, b"OFF");
+        state.push(SESSION_TRACK_SYSTEM_VARIABLES);
+        lenenc(&mut state, &item);
+        let mut item = vec![];
+        lenenc(&mut item, b"db1");
+        state.push(SESSION_TRACK_SCHEMA);
+        lenenc(&mut state, &item);
+        let mut item = vec![];
+        lenenc(&mut item, b"1");
+        state.push(SESSION_TRACK_STATE_CHANGE);
+        lenenc(&mut state, &item);
+        let mut item = vec![0];
+        lenenc(&mut item, b"3e11fa47-71ca-11e1-9e33-c80aa9429562:23");
+        state.push(SESSION_TRACK_GTIDS);
+        lenenc(&mut state, &item);
+        let mut item = vec![];
+        lenenc(&mut item, b"T_______");
+        state.push(SESSION_TRACK_TRANSACTION_STATE);
+        lenenc(&mut state, &item);
+        // 未知的类型忽略
+        state.push(0x7f);
+        lenenc(&mut state, b"??");
+        let mut tail = vec![0];
+        lenenc(&mut tail, &state);
+        let data = ok_packet(0x0002 | SERVER_SESSION_STATE_CHANGED, &tail);
+        let ok_packet = OkPacket::parse(&mut Cursor::new(data)).unwrap();
+        assert_eq!(ok_packet, OkPacket{
+            affected_rows: 1,
+            last_insert_id: 5,
+            status_flags: 0x0002 | SERVER_SESSION_STATE_CHANGED,
+            system_variables: vec![(String::from("autocommit"), String::from("OFF"))],
+            schema: Some(String::from("db1")),
+            state_changed: true,
+            gtids: Some(String::from("3e11fa47-71ca-11e1-9e33-c80aa9429562:23")),
+            transaction_state: Some(String::from("T_______")),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn ok_packet_with_session_track_and_no_state_change() {
+        let mut tail = vec![];
+        lenenc(&mut tail, b"Records: 2  Duplicates: 0  Warnings: 0");
+        let ok_packet = OkPacket::parse(&mut Cursor::new(ok_packet(0x0002, &tail))).unwrap();
+        assert_eq!(ok_packet.info, "Records: 2  Duplicates: 0  Warnings: 0");
+        assert!(!ok_packet.state_changed);
+    }
+
+    #[test]
+    fn ok_packets_of_one_request_are_merged() {
+        let mut ok_packet = OkPacket{ affected_rows: 2, last_insert_id: 7, warnings: 1,
+            schema: Some(String::from("db1")), ..Default::default() };
+        ok_packet.merge(OkPacket{ affected_rows: 3, status_flags: 0x0002, warnings: 2,
+            transaction_state: Some(String::from("T_______")), ..Default::default() });
+        assert_eq!(ok_packet, OkPacket{
+            affected_rows: 5,
+            last_insert_id: 7,
+            status_flags: 0x0002,
+            warnings: 3,
+            schema: Some(String::from("db1")),
+            transaction_state: Some(String::from("T_______")),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn use_statement() {
+        assert_eq!(use_statement_database("use db1"), Some(String::from("db1")));
+        assert_eq!(use_statement_database("  USE db1;"), Some(String::from("db1")));
+        assert_eq!(use_statement_database("Use\tdb1 ; "), Some(String::from("db1")));
+        assert_eq!(use_statement_database("use`db1`"), Some(String::from("db1")));
+        assert_eq!(use_statement_database("use `my db`;"), Some(String::from("my db")));
+        assert_eq!(use_statement_database("use `a``b`"), Some(String::from("a`b")));
+        assert_eq!(use_statement_database("use 库1"), Some(String::from("库1")));
+        assert_eq!(use_statement_database("/* app */ use db1"), Some(String::from("db1")));
+        assert_eq!(use_statement_database("/* a */ /* b */use db1"), Some(String::from("db1")));
+    }
+
+    #[test]
+    fn not_use_statement() {
+        assert_eq!(use_statement_database("USEdb"), None);
+        assert_eq!(use_statement_database("users"), None);
+        assert_eq!(use_statement_database("use"), None);
+        assert_eq!(use_statement_database("use ;"), None);
+        assert_eq!(use_statement_database("use `db1"), None);
+        assert_eq!(use_statement_database("use db1 db2"), None);
+        assert_eq!(use_statement_database("select 1; use db1"), None);
+        assert_eq!(use_statement_database("/* use db1"), None);
+        assert_eq!(use_statement_database("-- c\nuse db1"), None);
+        // 前3个字节落在多字节字符中间
+        assert_eq!(use_statement_database("éé"), None);
+        assert_eq!(use_statement_database("ué db1"), None);
+        assert_eq!(use_statement_database("库 db1"), None);
+        assert_eq!(use_statement_database(""), None);
+    }
+
+    ///
+    /// HandshakeV10协议版本之后的部分
+    fn greeting(server_version: &str, capability_flags: u32, character_set: u8, auth_plugin_data_len: u8, tail: &[u8]) -> Vec<u8> {
+        let mut data = server_version.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.extend_from_slice(b"abcdefgh\0");
+        data.extend_from_slice(&(capability_flags as u16).to_le_bytes());
+        data.push(character_set);
+        data.extend_from_slice(&0x0002u16.to_le_bytes());
+        data.extend_from_slice(&((capability_flags >> 16) as u16).to_le_bytes());
+        data.push(auth_plugin_data_len);
+        data.extend_from_slice(&[0; 10]);
+        data.extend_from_slice(tail);
+        data
+    }
+
+    #[test]
+    fn server_handshake_57() {
+        // 5.7默认的capability为0x81fff7ff, auth_plugin_data_len为21, 剩余13字节scramble(包括结尾的0)
+        let data = greeting("5.7.28-log", 0x81ff_f7ff, 0x08, 21, b"ijklmnopqrst\0mysql_native_password\0");
+        let handshake = ServerHandshake::parse(0x0a, &mut Cursor::new(data)).unwrap();
+        assert_eq!(handshake, ServerHandshake{
+            protocol_version: 0x0a,
+            server_version: String::from("5.7.28-log"),
+            thread_id: 7,
+            capability_flags: 0x81ff_f7ff,
+            character_set: 0x08,
+            status_flags: 0x0002,
+            auth_plugin_name: String::from("mysql_native_password"),
+        });
+    }
+
+    #[test]
+    fn server_handshake_80() {
+        let data = greeting("8.0.21", 0xc7ff_ffff, 0xff, 21, b"ijklmnopqrst\0caching_sha2_password\0");
+        let handshake = ServerHandshake::parse(0x0a, &mut Cursor::new(data)).unwrap();
+        assert_eq!(handshake.server_version, "8.0.21");
+        assert_eq!(handshake.thread_id, 7);
+        assert_eq!(handshake.capability_flags, 0xc7ff_ffff);
+        assert_eq!(handshake.character_set, 0xff);
+        assert_eq!(handshake.auth_plugin_name, "caching_sha2_password");
+    }
+
+    #[test]
+    fn server_handshake_auth_plugin_data_len() {
+        let capability_flags = CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH;
+        // scramble超过20字节时按len - 8跳过
+        let mut tail = vec![b'x'; 22];
+        tail.extend_from_slice(b"mysql_native_password\0");
+        let data = greeting("5.7.28", capability_flags, 0x21, 30, &tail);
+        let handshake = ServerHandshake::parse(0x0a, &mut Cursor::new(data)).unwrap();
+        assert_eq!(handshake.auth_plugin_name, "mysql_native_password");
+        // auth_plugin_data_len为0时至少跳过13字节
+        let mut tail = vec![b'x'; 13];
+        tail.extend_from_slice(b"mysql_native_password\0");
+        let data = greeting("5.7.28", capability_flags, 0x21, 0, &tail);
+        let handshake = ServerHandshake::parse(0x0a, &mut Cursor::new(data)).unwrap();
+        assert_eq!(handshake.auth_plugin_name, "mysql_native_password");
+        // 没有CLIENT_SECURE_CONNECTION时没有scramble的第二部分
+        let data = greeting("5.7.28", CLIENT_PROTOCOL_41 | CLIENT_PLUGIN_AUTH, 0x21, 0, b"mysql_native_password\0");
+        let handshake = ServerHandshake::parse(0x0a, &mut Cursor::new(data)).unwrap();
+        assert_eq!(handshake.auth_plugin_name, "mysql_native_password");
+    }
+
+    #[test]
+    fn server_handshake_without_plugin_name_nul() {
+        // 部分版本的auth_plugin_name没有结尾的0
+        let data = greeting("5.5.8", 0x800f_f7ff, 0x08, 21, b"ijklmnopqrst\0mysql_native_password");
+        let handshake = ServerHandshake::parse(0x0a, &mut Cursor::new(data)).unwrap();
+        assert_eq!(handshake.auth_plugin_name, "mysql_native_password");
+    }
+
+    #[test]
+    fn server_handshake_pre_41() {
+        // 较早的服务端在capability_flags_1之后结束
+        let mut data = b"3.23.58\0".to_vec();
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.extend_from_slice(b"abcdefgh\0");
+        data.extend_from_slice(&0x002cu16.to_le_bytes());
+        let handshake = ServerHandshake::parse(0x0a, &mut Cursor::new(data)).unwrap();
+        assert_eq!(handshake, ServerHandshake{
+            protocol_version: 0x0a,
+            server_version: String::from("3.23.58"),
+            thread_id: 7,
+            capability_flags: 0x002c,
+            ..Default::default()
+        });
+    }
+}
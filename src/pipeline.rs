@@ -0,0 +1,598 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/4/14
+*/
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
+use std::thread::{self, JoinHandle};
+use crate::Config;
+use crate::packet::{StreamPacket, StreamType};
+use crate::packet::network::{TCP_SYN, TCP_FIN, TCP_RST};
+use crate::packet::protocol::MysqlPacket;
+use crate::reassembly::TcpReassembler;
+use crate::flow::FlowKey;
+use crate::direction::ServerTable;
+use crate::dedup::DuplicateFilter;
+use crate::session::AllSessionInfo;
+use crate::runtime::AsyncSender;
+
+const WORKER_QUEUE_SIZE: usize = 8192;         // 每个worker线程的队列长度
+pub const OUTPUT_QUEUE_SIZE: usize = 8192;     // 输出线程及异步输出任务的队列长度
+
+///
+/// 队列的当前长度及发送、丢弃计数
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    pub depth: AtomicUsize,                     // 队列中等待处理的条目数
+    pub sent: AtomicU64,                        // 已发送的条目数
+    pub dropped: AtomicU64,                     // 队列满时丢弃的条目数
+}
+
+impl QueueStats {
+    pub fn report(&self, name: &str) {
+        eprintln!("{} queue: depth {}, sent {}, dropped {}", name,
+                  self.depth.load(Ordering::Relaxed),
+                  self.sent.load(Ordering::Relaxed),
+                  self.dropped.load(Ordering::Relaxed));
+    }
+
+    ///
+    /// 以prometheus文本格式输出
+    pub fn metrics(&self, name: &str, out: &mut String) {
+        out.push_str(&format!("testaa_queue_depth{{queue=\"{}\"}} {}\n", name, self.depth.load(Ordering::Relaxed)));
+        out.push_str(&format!("testaa_queue_sent_total{{queue=\"{}\"}} {}\n", name, self.sent.load(Ordering::Relaxed)));
+        out.push_str(&format!("testaa_queue_dropped_total{{queue=\"{}\"}} {}\n", name, self.dropped.load(Ordering::Relaxed)));
+    }
+}
+
+///
+/// 带计数的有界队列发送端
+#[derive(Debug)]
+pub struct QueueSender<T> {
+    tx: SyncSender<T>,
+    stats: Arc<QueueStats>,
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        QueueSender { tx: self.tx.clone(), stats: self.stats.clone() }
+    }
+}
+
+impl<T> QueueSender<T> {
+    ///
+    /// 阻塞发送, 队列满时等待接收端处理
+    pub fn send(&self, value: T) {
+        self.stats.depth.fetch_add(1, Ordering::Relaxed);
+        if self.tx.send(value).is_ok() {
+            self.stats.sent.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    ///
+    /// 非阻塞发送, 队列满时丢弃并计数
+    pub fn try_send(&self, value: T) {
+        self.stats.depth.fetch_add(1, Ordering::Relaxed);
+        if self.tx.try_send(value).is_ok() {
+            self.stats.sent.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+///
+/// 带计数的有界队列接收端
+pub struct QueueReceiver<T> {
+    rx: Receiver<T>,
+    stats: Arc<QueueStats>,
+}
+
+impl<T> QueueReceiver<T> {
+    ///
+    /// 阻塞接收, 所有发送端关闭后返回None
+    pub fn recv(&self) -> Option<T> {
+        let value = self.rx.recv().ok()?;
+        self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+        Some(value)
+    }
+}
+
+pub fn queue<T>(size: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (tx, rx) = sync_channel(size);
+    let stats = Arc::new(QueueStats::default());
+    (QueueSender { tx, stats: stats.clone() }, QueueReceiver { rx, stats })
+}
+
+///
+/// 审计记录的输出位置, 单线程时直接打印, 多线程时发送到输出线程统一打印
+/// 异步模式下发送到运行在事件循环中的输出任务
+#[derive(Debug, Clone)]
+pub enum Output {
+    Stdout,
+    Queue(QueueSender<String>),
+    Async(AsyncSender),
+}
+
+impl Output {
+    pub fn write<T: Debug>(&self, record: &T) {
+        match self {
+            Output::Stdout => println!("{:?}", record),
+            Output::Queue(tx) => tx.send(format!("{:?}", record)),
+            Output::Async(tx) => tx.send(format!("{:?}", record))
+        }
+    }
+}
+
+///
+/// 处理已解析的数据包: tcp重组、mysql解析及session/连接表维护
+/// 每个worker拥有独立的session表, 同一个连接的两个方向的包必须由同一个worker处理
+pub struct Worker {
+    name: String,                               // 输出计数信息时的前缀
+    conf: Config,
+    all_session_info: AllSessionInfo,
+    tcp_streams: TcpReassembler,
+    servers: ServerTable,
+    duplicates: DuplicateFilter,
+    decode_errors: u64,                         // 无法解析的mysql包
+    last_sec: u64,                              // 最近一次检查超时的包时间
+    last_report: u64,                           // 最近一次输出计数信息的包时间
+}
+
+impl Worker {
+    pub fn new(name: String, conf: &Config, output: Output) -> Worker {
+        Worker {
+            name,
+            conf: conf.clone(),
+            all_session_info: AllSessionInfo::new(conf.session_limits(), output),
+            tcp_streams: TcpReassembler::new(conf.max_sessions * 2),
+            servers: ServerTable::new(conf.max_sessions),
+            duplicates: DuplicateFilter::new(conf.dedup_window),
+            decode_errors: 0,
+            last_sec: 0,
+            last_report: 0,
+        }
+    }
+
+    pub fn process(&mut self, mut my_packet: StreamPacket) {
+        if my_packet.ts.tv_sec > self.last_sec {
+            // 以包时间为准每秒检查一次空闲超时, 离线文件也能按原始时间淘汰
+            self.last_sec = my_packet.ts.tv_sec;
+            self.all_session_info.expire(&my_packet.ts);
+            self.tcp_streams.expire(self.last_sec, self.conf.conn_idle_timeout);
+            self.servers.expire(self.last_sec, self.conf.conn_idle_timeout);
+            if self.last_report == 0 {
+                self.last_report = self.last_sec;
+            } else if self.conf.stats_interval > 0 && self.last_sec >= self.last_report + self.conf.stats_interval {
+                self.last_report = self.last_sec;
+                self.report();
+            }
+        }
+
+        if self.duplicates.is_duplicate(&my_packet) {                                       // 镜像流量中重复收到的帧
+            return;
+        }
+        let session_key = match my_packet.set_stream_type(&self.conf, &mut self.servers) {
+            Ok(v) => v,
+            Err(_) => {
+                self.decode_errors += 1;
+                return;
+            }
+        };
+        my_packet.max_sql_len = self.conf.max_sql_len;
+        let pure_ack = my_packet.payload.is_empty() && my_packet.packet_flag & (TCP_SYN | TCP_FIN | TCP_RST) == 0;
+        if !pure_ack {
+            self.all_session_info.connection_open(&my_packet, &session_key);                // syn/syn-ack/数据包记录连接信息
+        }
+        // 确认号越过反方向的缺口时, 反方向跳过缺口后的数据先于当前包处理
+        let peer_type = my_packet.s_type.reverse();
+        let rows = self.all_session_info.receiving_rows(&peer_type, &session_key);
+        let gaps = self.tcp_streams.stats.gaps;
+        let mysql_packets = self.tcp_streams.ack(&my_packet, rows);
+        let gap_skipped = self.tcp_streams.stats.gaps > gaps;
+        if gap_skipped || !mysql_packets.is_empty() {
+            let s_type = std::mem::replace(&mut my_packet.s_type, peer_type);
+            self.unpacket(&mut my_packet, &session_key, mysql_packets, gap_skipped);
+            my_packet.s_type = s_type;
+        }
+        if pure_ack {
+            return;
+        }
+        let rows = self.all_session_info.receiving_rows(&my_packet.s_type, &session_key);
+        let gaps = self.tcp_streams.stats.gaps;
+        let mysql_packets = self.tcp_streams.push(&my_packet, rows);                        // tcp重组
+        let gap_skipped = self.tcp_streams.stats.gaps > gaps;
+        self.unpacket(&mut my_packet, &session_key, mysql_packets, gap_skipped);
+        if self.all_session_info.connection_close(&my_packet, &session_key) {               // fin/rst, 连接结束后删除重组状态
+            self.tcp_streams.remove(&my_packet);
+        }
+    }
+
+    ///
+    /// 逐个解析重组后完整的mysql包, my_packet的方向为mysql包所属数据流的方向
+    /// 返回方向跳过了缺口时, 丢失的数据属于当前等待返回的请求, 先将其作为未完成的记录输出, 缺口之后的返回交给排队的下一个请求
+    fn unpacket(&mut self, my_packet: &mut StreamPacket, session_key: &FlowKey, mysql_packets: Vec<MysqlPacket>, gap_skipped: bool) {
+        if gap_skipped {
+            if let StreamType::Response = my_packet.s_type {
+                self.all_session_info.abandon(session_key);
+            }
+        }
+        for mysql_packet in mysql_packets {
+            my_packet.set_payload(mysql_packet);
+            if my_packet.get_mysql_protocol_header().is_err() {                             // 获取mysql协议header部分
+                self.decode_errors += 1;
+                continue;
+            }
+            if my_packet.op_session_info(session_key, &mut self.all_session_info).is_err() {
+                self.decode_errors += 1;
+            }
+        }
+    }
+
+    ///
+    /// 输出重组计数及各表大小到stderr, 用于监控内存占用
+    pub fn report(&mut self) {
+        eprintln!("{}{:?}, streams: {}, servers: {}, decode_errors: {}", self.name, self.tcp_streams.stats,
+                  self.tcp_streams.streams.len(), self.servers.len(), self.decode_errors);
+        eprintln!("{}{:?}, frames: {}", self.name, self.duplicates.stats, self.duplicates.len());
+        eprintln!("{}{:?}", self.name, self.all_session_info.table_stats());
+    }
+
+    ///
+    /// 以prometheus文本格式输出重组计数及各表大小
+    pub fn metrics(&mut self, out: &mut String) {
+        let table = self.all_session_info.table_stats();
+        let reassembly = &self.tcp_streams.stats;
+        for (name, value) in &[("sessions", table.sessions as u64),
+                               ("pipelined_sessions", table.pipelined as u64),
+                               ("connections", table.connections as u64),
+                               ("streams", self.tcp_streams.streams.len() as u64),
+                               ("servers", self.servers.len() as u64),
+                               ("evicted_idle_total", table.evicted_idle),
+                               ("evicted_lru_total", table.evicted_lru),
+                               ("decode_errors_total", self.decode_errors),
+                               ("duplicate_frames_total", self.duplicates.stats.dropped),
+                               ("out_of_order_total", reassembly.out_of_order),
+                               ("duplicate_segments_total", reassembly.duplicates),
+                               ("gaps_total", reassembly.gaps)] {
+            out.push_str(&format!("testaa_{} {}\n", name, value));
+        }
+    }
+}
+
+///
+/// 多线程处理: 抓包线程按连接hash分发到worker线程, worker线程的审计记录发送到输出线程
+/// 监听网卡时队列满则丢弃数据包并计数, 不阻塞抓包; 读取离线文件时等待worker处理
+/// 指定了output时(异步模式)不创建输出线程
+pub struct Pipeline {
+    blocking: bool,
+    workers: Vec<QueueSender<StreamPacket<'static>>>,
+    handles: Vec<JoinHandle<()>>,
+    output: Option<(QueueSender<String>, JoinHandle<()>)>,
+}
+
+impl Pipeline {
+    pub fn new(conf: &Config, output: Option<Output>) -> Result<Pipeline, Box<dyn Error>> {
+        let (worker_output, output) = match output {
+            Some(v) => (v, None),
+            None => {
+                let (tx, rx) = queue::<String>(OUTPUT_QUEUE_SIZE);
+                let handle = thread::Builder::new().name("output".to_string()).spawn(move || {
+                    let stdout = io::stdout();
+                    let mut out = stdout.lock();
+                    while let Some(line) = rx.recv() {
+                        if writeln!(out, "{}", line).is_err() {
+                            break;
+                        }
+                    }
+                })?;
+                (Output::Queue(tx.clone()), Some((tx, handle)))
+            }
+        };
+        let mut workers = vec![];
+        let mut handles = vec![];
+        for i in 0..conf.threads {
+            let (tx, rx) = queue::<StreamPacket<'static>>(WORKER_QUEUE_SIZE);
+            let mut worker = Worker::new(format!("worker{}: ", i), conf, worker_output.clone());
+            handles.push(thread::Builder::new().name(format!("worker{}", i)).spawn(move || {
+                while let Some(packet) = rx.recv() {
+                    worker.process(packet);
+                }
+                worker.report();
+            })?);
+            workers.push(tx);
+        }
+        Ok(Pipeline { blocking: conf.read_file.is_some(), workers, handles, output })
+    }
+
+    ///
+    /// 按连接分发, 两个方向的包使用相同的hash
+    pub fn dispatch(&self, packet: StreamPacket) {
+        let key = packet.stream_key(false);
+        let mut hasher = DefaultHasher::new();
+        key.min(key.reverse()).hash(&mut hasher);
+        let shard = (hasher.finish() % self.workers.len() as u64) as usize;
+        if self.blocking {
+            self.workers[shard].send(packet.into_owned());
+        } else {
+            self.workers[shard].try_send(packet.into_owned());
+        }
+    }
+
+    pub fn report(&self) {
+        for (i, worker) in self.workers.iter().enumerate() {
+            worker.stats.report(&format!("worker{}", i));
+        }
+        if let Some((output, _)) = &self.output {
+            output.stats.report("output");
+        }
+    }
+
+    pub fn metrics(&self, out: &mut String) {
+        for (i, worker) in self.workers.iter().enumerate() {
+            worker.stats.metrics(&format!("worker{}", i), out);
+        }
+        if let Some((output, _)) = &self.output {
+            output.stats.metrics("output", out);
+        }
+    }
+
+    ///
+    /// 关闭队列并等待所有线程处理完剩余的数据
+    pub fn finish(self) {
+        let Pipeline { workers, handles, output, .. } = self;
+        let worker_stats: Vec<Arc<QueueStats>> = workers.iter().map(|w| w.stats.clone()).collect();
+        drop(workers);
+        for handle in handles {
+            let _ = handle.join();
+        }
+        for (i, stats) in worker_stats.iter().enumerate() {
+            stats.report(&format!("worker{}", i));
+        }
+        if let Some((output, output_handle)) = output {
+            let output_stats = output.stats.clone();
+            drop(output);
+            let _ = output_handle.join();
+            output_stats.report("output");
+        }
+    }
+}
+
+///
+/// 单线程时在抓包线程中直接处理, 多线程时分发到worker线程
+/// 同时监听多个网卡时各抓包线程通过Shared共用同一组worker, 只有一个线程输出队列计数
+pub enum Dispatcher {
+    Inline(Box<Worker>),
+    Sharded(Pipeline),
+    Shared(Arc<Pipeline>, bool),
+}
+
+impl Dispatcher {
+    ///
+    /// output为None时单线程直接打印, 多线程时创建输出线程
+    pub fn new(conf: &Config, output: Option<Output>) -> Result<Dispatcher, Box<dyn Error>> {
+        if conf.threads > 1 || conf.interfaces() > 1 {
+            Ok(Dispatcher::Sharded(Pipeline::new(conf, output)?))
+        } else {
+            let output = output.unwrap_or(Output::Stdout);
+            Ok(Dispatcher::Inline(Box::new(Worker::new(String::new(), conf, output))))
+        }
+    }
+
+    pub fn dispatch(&mut self, packet: StreamPacket) {
+        match self {
+            Dispatcher::Inline(worker) => worker.process(packet),
+            Dispatcher::Sharded(pipeline) => pipeline.dispatch(packet),
+            Dispatcher::Shared(pipeline, _) => pipeline.dispatch(packet)
+        }
+    }
+
+    ///
+    /// 生成count个供抓包线程共用的分发端, 第一个负责输出队列计数
+    /// 单线程时没有可共用的队列, 返回空列表
+    pub fn share(self, count: usize) -> (Dispatcher, Vec<Dispatcher>) {
+        match self {
+            Dispatcher::Sharded(pipeline) => {
+                let pipeline = Arc::new(pipeline);
+                let shared = (0..count).map(|i| Dispatcher::Shared(pipeline.clone(), i == 0)).collect();
+                (Dispatcher::Shared(pipeline, false), shared)
+            }
+            other => (other, vec![])
+        }
+    }
+
+    ///
+    /// 其他共用的分发端都已释放后取回pipeline, 用于结束时等待worker处理完毕
+    pub fn unshare(self) -> Dispatcher {
+        match self {
+            Dispatcher::Shared(pipeline, report) => match Arc::try_unwrap(pipeline) {
+                Ok(v) => Dispatcher::Sharded(v),
+                Err(v) => Dispatcher::Shared(v, report)
+            },
+            other => other
+        }
+    }
+
+    ///
+    /// 输出队列计数, 单线程时没有队列
+    pub fn report(&self) {
+        match self {
+            Dispatcher::Sharded(pipeline) => pipeline.report(),
+            Dispatcher::Shared(pipeline, true) => pipeline.report(),
+            _ => {}
+        }
+    }
+
+    ///
+    /// 以prometheus文本格式输出, 多线程时各worker的表在其他线程中, 只输出队列计数
+    pub fn metrics(&mut self, out: &mut String) {
+        match self {
+            Dispatcher::Inline(worker) => worker.metrics(out),
+            Dispatcher::Sharded(pipeline) => pipeline.metrics(out),
+            Dispatcher::Shared(pipeline, _) => pipeline.metrics(out)
+        }
+    }
+
+    pub fn finish(self) {
+        match self {
+            Dispatcher::Inline(mut worker) => worker.report(),
+            Dispatcher::Sharded(pipeline) => pipeline.finish(),
+            Dispatcher::Shared(..) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+    use crate::Opt;
+    use crate::capture::RawPacket;
+    use crate::packet::UnixTime;
+    use crate::packet::network::{LinkType, TCP_ACK};
+
+    const CLIENT_ISN: u32 = 1000;
+    const SERVER_ISN: u32 = 5000;
+
+    fn worker() -> (Worker, QueueReceiver<String>) {
+        let conf = Config::new(Opt::from_iter(&["testaa", "-r", "test.pcap", "-p", "3306"])).unwrap();
+        let (tx, rx) = queue(64);
+        (Worker::new(String::new(), &conf, Output::Queue(tx)), rx)
+    }
+
+    fn mysql_packet(seq_id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = (payload.len() as u32).to_le_bytes()[..3].to_vec();
+        data.push(seq_id);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn ok_packet(affected_rows: u8) -> Vec<u8> {
+        mysql_packet(1, &[0x00, affected_rows, 0, 0x02, 0, 0, 0])
+    }
+
+    ///
+    /// 客户端10.0.0.2:40000与服务端10.0.0.1:3306之间的ipv4/tcp包, request为客户端发出的包
+    fn process(worker: &mut Worker, request: bool, flag: u8, seq: u32, ack: u32, payload: &[u8]) {
+        let (source, destination, ports) = if request {
+            ([10, 0, 0, 2], [10, 0, 0, 1], [40000u16, 3306])
+        } else {
+            ([10, 0, 0, 1], [10, 0, 0, 2], [3306, 40000])
+        };
+        let mut data = vec![0x45, 0];
+        data.extend_from_slice(&((40 + payload.len()) as u16).to_be_bytes());
+        data.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        data.extend_from_slice(&source);
+        data.extend_from_slice(&destination);
+        data.extend_from_slice(&ports[0].to_be_bytes());
+        data.extend_from_slice(&ports[1].to_be_bytes());
+        data.extend_from_slice(&seq.to_be_bytes());
+        data.extend_from_slice(&ack.to_be_bytes());
+        data.extend_from_slice(&[0x50, flag, 0xff, 0xff, 0, 0, 0, 0]);
+        data.extend_from_slice(payload);
+        let raw = RawPacket { ts: UnixTime { tv_sec: 1, tv_usec: 0 }, len: data.len() as u32, data: &data };
+        worker.process(StreamPacket::new(&raw, LinkType::Raw).unwrap().unwrap());
+    }
+
+    ///
+    /// 完成三次握手, 返回客户端与服务端下一个数据的序列号
+    fn connect(worker: &mut Worker) -> (u32, u32) {
+        process(worker, true, TCP_SYN, CLIENT_ISN, 0, &[]);
+        process(worker, false, TCP_SYN | TCP_ACK, SERVER_ISN, CLIENT_ISN + 1, &[]);
+        process(worker, true, TCP_ACK, CLIENT_ISN + 1, SERVER_ISN + 1, &[]);
+        (CLIENT_ISN + 1, SERVER_ISN + 1)
+    }
+
+    ///
+    /// 输出的请求记录, 不包括连接事件
+    fn sessions(rx: &QueueReceiver<String>) -> Vec<String> {
+        rx.rx.try_iter().filter(|r| r.contains("execute_sql")).collect()
+    }
+
+    #[test]
+    fn pure_ack_releases_data_after_a_lost_segment() {
+        let (mut worker, rx) = worker();
+        let (c, s) = connect(&mut worker);
+        let insert_1 = mysql_packet(0, b"\x03insert 1");
+        let insert_2 = mysql_packet(0, b"\x03insert 2");
+        process(&mut worker, true, TCP_ACK, c, s, &insert_1);
+        process(&mut worker, true, TCP_ACK, c + 13, s, &insert_2);
+        // 第一个请求的返回抓包丢失, 第二个请求的返回乱序缓存
+        process(&mut worker, false, TCP_ACK, s + 11, c + 26, &ok_packet(2));
+        assert!(sessions(&rx).is_empty());
+        assert_eq!(worker.tcp_streams.stats.out_of_order, 1);
+        // 客户端确认了两个返回, 缺口不会再补齐, 不等下一个数据包立即输出
+        process(&mut worker, true, TCP_ACK, c + 26, s + 22, &[]);
+        assert_eq!(worker.tcp_streams.stats.gaps, 1);
+        assert_eq!(worker.tcp_streams.stats.gap_bytes, 11);
+        assert!(sessions(&rx)[0].contains(r#"execute_sql: "insert 1""#));
+    }
+
+    #[test]
+    fn lost_response_is_written_incomplete() {
+        let (mut worker, rx) = worker();
+        let (mut c, mut s) = connect(&mut worker);
+        // 第一个请求的返回抓包丢失, 下一个请求的确认号说明客户端已收到
+        process(&mut worker, true, TCP_ACK, c, s, &mysql_packet(0, b"\x03insert 1"));
+        s += 11;
+        c += 13;
+        for (sql, affected_rows) in &[("insert 2", 2), ("insert 3", 3)] {
+            let request = mysql_packet(0, format!("\x03{}", sql).as_bytes());
+            process(&mut worker, true, TCP_ACK, c, s, &request);
+            c += request.len() as u32;
+            process(&mut worker, false, TCP_ACK, s, c, &ok_packet(*affected_rows));
+            s += 11;
+        }
+        let sessions = sessions(&rx);
+        assert_eq!(sessions.len(), 3);
+        assert!(sessions[0].contains(r#"execute_sql: "insert 1""#));
+        assert!(sessions[0].contains("ok_packet: None") && sessions[0].contains("incomplete: true"));
+        assert!(sessions[1].contains(r#"execute_sql: "insert 2""#) && sessions[1].contains("affected_rows: 2"));
+        assert!(sessions[2].contains(r#"execute_sql: "insert 3""#) && sessions[2].contains("affected_rows: 3"));
+        assert!(sessions[1..].iter().all(|r| r.contains("incomplete: false")));
+    }
+
+    #[test]
+    fn lost_pipelined_response_is_written_incomplete() {
+        let (mut worker, rx) = worker();
+        let (c, s) = connect(&mut worker);
+        let mut offset = 0;
+        for sql in &["insert 1", "insert 2", "insert 3"] {
+            let request = mysql_packet(0, format!("\x03{}", sql).as_bytes());
+            process(&mut worker, true, TCP_ACK, c + offset, s, &request);
+            offset += request.len() as u32;
+        }
+        // 第一个请求的返回抓包丢失, 之后的返回乱序缓存, 客户端确认后跳过缺口
+        process(&mut worker, false, TCP_ACK, s + 11, c + offset, &ok_packet(2));
+        process(&mut worker, false, TCP_ACK, s + 22, c + offset, &ok_packet(3));
+        assert!(sessions(&rx).is_empty());
+        process(&mut worker, true, TCP_ACK, c + offset, s + 33, &[]);
+        let sessions = sessions(&rx);
+        assert_eq!(sessions.len(), 3);
+        assert!(sessions[0].contains(r#"execute_sql: "insert 1""#) && sessions[0].contains("incomplete: true"));
+        assert!(sessions[1].contains(r#"execute_sql: "insert 2""#) && sessions[1].contains("affected_rows: 2"));
+        assert!(sessions[2].contains(r#"execute_sql: "insert 3""#) && sessions[2].contains("affected_rows: 3"));
+    }
+
+    #[test]
+    fn pure_ack_after_close_keeps_no_state() {
+        let (mut worker, _rx) = worker();
+        let (c, s) = connect(&mut worker);
+        process(&mut worker, true, TCP_FIN | TCP_ACK, c, s, &[]);
+        process(&mut worker, false, TCP_FIN | TCP_ACK, s, c + 1, &[]);
+        assert!(worker.tcp_streams.streams.is_empty());
+        process(&mut worker, true, TCP_ACK, c + 1, s + 1, &[]);
+        assert!(worker.tcp_streams.streams.is_empty());
+        assert_eq!(worker.all_session_info.table_stats().connections, 0);
+        assert_eq!(worker.tcp_streams.stats.gaps, 0);
+    }
+}
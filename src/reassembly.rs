@@ -0,0 +1,534 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/4/6
+*/
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use crate::packet::{StreamPacket, StreamType};
+use crate::packet::network::{TCP_SYN, TCP_FIN, TCP_RST, TCP_ACK};
+use crate::packet::protocol::{MysqlPacketAssembler, MysqlPacket};
+use crate::lru::LruIndex;
+use crate::flow::FlowKey;
+
+const MAX_BUFFERED_BYTES: usize = 4 * 1024 * 1024;     // 单个方向最多缓存的乱序数据量
+const MAX_BUFFERED_SEGMENTS: usize = 1024;              // 单个方向最多缓存的乱序分段数
+const MAX_GAP_WAIT_SECS: u64 = 10;                      // 缺口最多等待重传的秒数
+
+///
+/// 重组过程中的计数信息
+#[derive(Debug, Default, Clone)]
+pub struct ReassemblyStats {
+    pub out_of_order: u64,          // 乱序到达并缓存的分段
+    pub duplicates: u64,            // 完全重复(重传)的分段
+    pub gaps: u64,                  // 无法补齐而跳过的缺口次数
+    pub gap_bytes: u64,             // 跳过的缺口字节数
+    pub evicted: u64,               // 空闲超时或超过容量被淘汰的数据流
+}
+
+///
+/// 单方向tcp数据流的重组状态
+/// 以下一个期望的序列号为基准, 乱序分段按相对偏移缓存, 补齐后按顺序输出
+///
+/// 抓包丢失的分段不会再出现, 以下情况跳过缺口:
+///     对端已确认缺口之后的数据, 说明对端已收到缺口部分, 不会再重传, 没有缓存分段时直接跳到确认号
+///     缺口等待超过MAX_GAP_WAIT_SECS秒
+///     缓存超过MAX_BUFFERED_BYTES或MAX_BUFFERED_SEGMENTS
+#[derive(Debug)]
+pub struct TcpStream {
+    next_seq: Option<u32>,                  // 下一个期望的序列号, 未见到syn时以第一个数据包为准
+    offset: u64,                            // 已输出的字节数, 即next_seq对应的相对偏移
+    segments: BTreeMap<u64, Vec<u8>>,       // 乱序缓存, 键为相对偏移
+    buffered: usize,                        // 缓存的字节数
+    acked: Option<u32>,                     // 对端确认过的最大序列号
+    gap_since: Option<u64>,                 // 开始等待缺口补齐的时间秒
+    fin: bool,                              // 已收到fin, fin占用一个序列号
+    mysql: MysqlPacketAssembler,            // 已按顺序重组的数据切分为mysql逻辑包
+}
+
+impl TcpStream {
+    pub fn new() -> TcpStream {
+        TcpStream {
+            next_seq: None,
+            offset: 0,
+            segments: BTreeMap::new(),
+            buffered: 0,
+            acked: None,
+            gap_since: None,
+            fin: false,
+            mysql: MysqlPacketAssembler::new(),
+        }
+    }
+
+    ///
+    /// 记录对端发送的确认号, 返回跳过缺口后可以按顺序输出的数据
+    /// 确认号越过期望的序列号时, 其间的数据对端已收到, 不会再重传:
+    ///     有缓存分段时跳过缺口输出缓存的数据, 缓存之后仍未到达确认号的部分等下一个确认号
+    ///     没有缓存分段时直接跳到确认号, 已缓存的不完整mysql包丢弃
+    /// 还未见到数据时以确认号作为期望的序列号
+    pub fn ack(&mut self, ack: u32, now: u64, stats: &mut ReassemblyStats) -> Vec<u8> {
+        match self.acked {
+            Some(v) if (ack.wrapping_sub(v) as i32) <= 0 => {}
+            _ => self.acked = Some(ack),
+        }
+        let next_seq = match self.next_seq {
+            Some(v) => v,
+            None => {
+                self.next_seq = Some(ack);
+                return vec![];
+            }
+        };
+        if !self.gap_acked(next_seq) {
+            return vec![];
+        }
+        if !self.segments.is_empty() {
+            let mut out = vec![];
+            while !self.segments.is_empty() && matches!(self.next_seq, Some(v) if self.gap_acked(v)) {
+                out.extend(self.skip_gap(now, stats));
+            }
+            return out;
+        }
+        let acked = self.acked.unwrap_or(next_seq);
+        if self.fin && acked == next_seq.wrapping_add(1) {
+            // 对端确认的是fin
+            return vec![];
+        }
+        let skipped = acked.wrapping_sub(next_seq) as usize;
+        stats.gaps += 1;
+        stats.gap_bytes += skipped as u64;
+        self.advance(skipped);
+        self.mysql.reset();
+        vec![]
+    }
+
+    ///
+    /// 加入一个分段, 返回当前可以按顺序输出的数据
+    /// 按顺序到达且没有乱序缓存时直接返回分段数据的切片, 不做复制
+    /// now为包时间秒, 用于判断缺口等待是否超时
+    pub fn push<'p>(&mut self, flag: u8, seq: u32, payload: &'p [u8], now: u64, stats: &mut ReassemblyStats) -> Cow<'p, [u8]> {
+        if flag & TCP_SYN != 0 {
+            // syn占用一个序列号, 数据从isn+1开始
+            self.next_seq = Some(seq.wrapping_add(1));
+            self.offset = 0;
+            self.segments.clear();
+            self.buffered = 0;
+            self.acked = None;
+            self.gap_since = None;
+            self.fin = false;
+            self.mysql.reset();
+            return Cow::Borrowed(&[]);
+        }
+        if flag & TCP_FIN != 0 {
+            self.fin = true;
+        }
+        if payload.is_empty() || flag & TCP_RST != 0 {
+            return Cow::Borrowed(&[]);
+        }
+        let next_seq = *self.next_seq.get_or_insert(seq);
+        let diff = seq.wrapping_sub(next_seq) as i32;
+        if diff < 0 && payload.len() as i64 <= -(diff as i64) {
+            // 已经输出过的数据, 为重传包
+            stats.duplicates += 1;
+            return Cow::Borrowed(&[]);
+        }
+        let seg_offset = (self.offset as i64 + diff as i64) as u64;
+        if diff > 0 {
+            // 乱序, 缓存等待缺口补齐
+            if !self.segments.contains_key(&seg_offset) {
+                stats.out_of_order += 1;
+                self.buffered += payload.len();
+                self.segments.insert(seg_offset, payload.to_vec());
+            } else {
+                stats.duplicates += 1;
+            }
+            let gap_since = *self.gap_since.get_or_insert(now);
+            if self.gap_acked(next_seq) || now >= gap_since + MAX_GAP_WAIT_SECS
+                || self.buffered > MAX_BUFFERED_BYTES || self.segments.len() > MAX_BUFFERED_SEGMENTS {
+                // 缺口不会再补齐, 跳过缺口从最小的缓存分段继续
+                return Cow::Owned(self.skip_gap(now, stats));
+            }
+            return Cow::Borrowed(&[]);
+        }
+        let data = &payload[(-diff.min(0)) as usize..];
+        self.advance(data.len());
+        if self.segments.is_empty() {
+            return Cow::Borrowed(data);
+        }
+        let mut out = data.to_vec();
+        self.drain_segments(&mut out, now, stats);
+        Cow::Owned(out)
+    }
+
+    ///
+    /// 对端已确认到期望的序列号之后, 缺口部分已被对端收到
+    fn gap_acked(&self, next_seq: u32) -> bool {
+        match self.acked {
+            Some(v) => (v.wrapping_sub(next_seq) as i32) > 0,
+            None => false,
+        }
+    }
+
+    ///
+    /// 输出数据后推进期望的序列号
+    fn advance(&mut self, len: usize) {
+        self.offset += len as u64;
+        self.next_seq = self.next_seq.map(|s| s.wrapping_add(len as u32));
+    }
+
+    ///
+    /// 将已与输出数据连续的缓存分段按顺序追加, 去掉重叠部分
+    /// 仍有缓存分段时从now开始重新等待下一个缺口
+    fn drain_segments(&mut self, out: &mut Vec<u8>, now: u64, stats: &mut ReassemblyStats) {
+        while let Some((&seg_offset, _)) = self.segments.iter().next() {
+            if seg_offset > self.offset {
+                break;
+            }
+            let data = self.segments.remove(&seg_offset).unwrap_or_default();
+            self.buffered -= data.len();
+            let overlap = (self.offset - seg_offset) as usize;
+            if overlap >= data.len() {
+                stats.duplicates += 1;
+                continue;
+            }
+            out.extend_from_slice(&data[overlap..]);
+            self.advance(data.len() - overlap);
+        }
+        self.gap_since = if self.segments.is_empty() { None } else { Some(now) };
+    }
+
+    ///
+    /// 跳过缺口, 从最小偏移的缓存分段开始输出
+    fn skip_gap(&mut self, now: u64, stats: &mut ReassemblyStats) -> Vec<u8> {
+        let first = match self.segments.keys().next() {
+            Some(v) => *v,
+            None => return vec![],
+        };
+        let skipped = first - self.offset;
+        stats.gaps += 1;
+        stats.gap_bytes += skipped;
+        self.advance(skipped as usize);
+        // 缺口之前不完整的mysql包已无法解析
+        self.mysql.reset();
+        let mut out = vec![];
+        self.drain_segments(&mut out, now, stats);
+        out
+    }
+}
+
+///
+/// 所有连接的两个方向的重组状态, 以数据包方向的四元组作为键
+/// 未见到fin/rst的数据流在空闲超时或超过容量时淘汰
+#[derive(Debug)]
+pub struct TcpReassembler {
+    pub streams: HashMap<FlowKey, TcpStream>,
+    pub stats: ReassemblyStats,
+    max_streams: usize,                 // 最多保存的数据流数
+    lru: LruIndex<FlowKey>,
+}
+
+impl TcpReassembler {
+    pub fn new(max_streams: usize) -> TcpReassembler {
+        TcpReassembler {
+            streams: HashMap::new(),
+            stats: ReassemblyStats::default(),
+            max_streams,
+            lru: LruIndex::new(),
+        }
+    }
+
+    ///
+    /// 将数据包的tcp数据加入对应方向的数据流, 返回重组后所有完整的mysql逻辑包
+    /// rows为true时该方向正在返回结果集的行数据, 行数据只保留首字节
+    /// 请求方向的sql文本最多保留max_sql_len字节
+    pub fn push(&mut self, stream_packet: &StreamPacket, rows: bool) -> Vec<MysqlPacket> {
+        let key = stream_packet.stream_key(false);
+        self.touch(&key, stream_packet.ts.tv_sec);
+        let stream = self.streams.entry(key)
+            .or_insert_with(TcpStream::new);
+        let data = stream.push(stream_packet.packet_flag, stream_packet.seq,
+                               &stream_packet.payload, stream_packet.ts.tv_sec, &mut self.stats);
+        if data.is_empty() {
+            return vec![];
+        }
+        stream.mysql.push(&data, rows, max_query_len(&stream_packet.s_type, stream_packet.max_sql_len))
+    }
+
+    ///
+    /// 确认号属于反方向的数据流, 确认号越过缺口时返回反方向跳过缺口后重组的mysql逻辑包
+    /// rows为反方向是否正在返回结果集的行数据
+    /// 带数据的包同时创建反方向的数据流, 纯ack包只更新已存在的数据流, 连接断开后的ack不会重新创建
+    pub fn ack(&mut self, stream_packet: &StreamPacket, rows: bool) -> Vec<MysqlPacket> {
+        if stream_packet.packet_flag & TCP_ACK == 0 {
+            return vec![];
+        }
+        let key = stream_packet.stream_key(true);
+        if stream_packet.payload.is_empty() && !self.streams.contains_key(&key) {
+            return vec![];
+        }
+        self.touch(&key, stream_packet.ts.tv_sec);
+        let stream = self.streams.entry(key)
+            .or_insert_with(TcpStream::new);
+        let data = stream.ack(stream_packet.ack, stream_packet.ts.tv_sec, &mut self.stats);
+        if data.is_empty() {
+            return vec![];
+        }
+        stream.mysql.push(&data, rows, max_query_len(&stream_packet.s_type.reverse(), stream_packet.max_sql_len))
+    }
+
+    ///
+    /// 记录数据流的访问时间, 新的数据流超过容量时淘汰最久未访问的数据流
+    fn touch(&mut self, key: &FlowKey, now: u64) {
+        if !self.streams.contains_key(key) && self.streams.len() >= self.max_streams {
+            if let Some(oldest) = self.lru.pop_oldest() {
+                self.streams.remove(&oldest);
+                self.stats.evicted += 1;
+            }
+        }
+        self.lru.touch(key, now);
+    }
+
+    ///
+    /// 连接断开后删除两个方向的重组状态
+    pub fn remove(&mut self, stream_packet: &StreamPacket) {
+        for key in &[stream_packet.stream_key(false), stream_packet.stream_key(true)] {
+            self.streams.remove(key);
+            self.lru.remove(key);
+        }
+    }
+
+    ///
+    /// 删除超过timeout秒没有数据的数据流
+    pub fn expire(&mut self, now: u64, timeout: u64) {
+        for key in self.lru.pop_idle(now, timeout) {
+            self.streams.remove(&key);
+            self.stats.evicted += 1;
+        }
+    }
+}
+
+///
+/// 请求方向的sql文本最多保留max_sql_len字节, 返回方向不截断
+fn max_query_len(s_type: &StreamType, max_sql_len: usize) -> Option<usize> {
+    match s_type {
+        StreamType::Request => Some(max_sql_len),
+        StreamType::Response => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::protocol::MAX_PAYLOAD_LEN;
+
+    const ISN: u32 = u32::MAX - 2;              // 数据跨越序列号回绕
+
+    fn stream() -> TcpStream {
+        let mut stream = TcpStream::new();
+        stream.push(TCP_SYN, ISN, &[], 0, &mut ReassemblyStats::default());
+        stream
+    }
+
+    fn seq(offset: u32) -> u32 {
+        ISN.wrapping_add(1).wrapping_add(offset)
+    }
+
+    fn mysql_packet(seq_id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = (payload.len() as u32).to_le_bytes()[..3].to_vec();
+        data.push(seq_id);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn in_order_segments_are_not_copied() {
+        let mut stats = ReassemblyStats::default();
+        let mut stream = stream();
+        assert!(matches!(stream.push(TCP_ACK, seq(0), b"abc", 0, &mut stats), Cow::Borrowed(b"abc")));
+        assert!(matches!(stream.push(TCP_ACK, seq(3), b"def", 0, &mut stats), Cow::Borrowed(b"def")));
+    }
+
+    #[test]
+    fn out_of_order_segments_are_buffered_until_the_gap_is_filled() {
+        let mut stats = ReassemblyStats::default();
+        let mut stream = stream();
+        assert!(stream.push(TCP_ACK, seq(6), b"ghi", 0, &mut stats).is_empty());
+        assert!(stream.push(TCP_ACK, seq(3), b"def", 0, &mut stats).is_empty());
+        assert_eq!(&*stream.push(TCP_ACK, seq(0), b"abc", 0, &mut stats), b"abcdefghi");
+        assert_eq!(stats.out_of_order, 2);
+        assert_eq!(stats.gaps, 0);
+        assert!(matches!(stream.push(TCP_ACK, seq(9), b"j", 0, &mut stats), Cow::Borrowed(b"j")));
+    }
+
+    #[test]
+    fn overlapping_and_retransmitted_segments_are_trimmed() {
+        let mut stats = ReassemblyStats::default();
+        let mut stream = stream();
+        assert_eq!(&*stream.push(TCP_ACK, seq(0), b"abcdef", 0, &mut stats), b"abcdef");
+        // 与已输出数据部分重叠
+        assert_eq!(&*stream.push(TCP_ACK, seq(3), b"defghi", 0, &mut stats), b"ghi");
+        // 完全重复的重传
+        assert!(stream.push(TCP_ACK, seq(0), b"abcdef", 0, &mut stats).is_empty());
+        assert_eq!(stats.duplicates, 1);
+        // 缓存分段与补齐缺口的分段重叠
+        assert!(stream.push(TCP_ACK, seq(12), b"mnop", 0, &mut stats).is_empty());
+        assert_eq!(&*stream.push(TCP_ACK, seq(9), b"jklmn", 0, &mut stats), b"jklmnop");
+        assert_eq!(stats.gaps, 0);
+    }
+
+    #[test]
+    fn gap_is_skipped_once_the_peer_acks_past_it() {
+        let mut stats = ReassemblyStats::default();
+        let mut stream = stream();
+        assert!(stream.push(TCP_ACK, seq(5), b"fgh", 0, &mut stats).is_empty());
+        // 确认号未越过缺口时继续等待
+        assert!(stream.ack(seq(0), 0, &mut stats).is_empty());
+        assert!(stream.push(TCP_ACK, seq(8), b"ij", 0, &mut stats).is_empty());
+        // 确认号越过缺口时立即输出缓存的数据, 不等下一个分段
+        assert_eq!(stream.ack(seq(10), 0, &mut stats), b"fghij");
+        assert_eq!(stats.gaps, 1);
+        assert_eq!(stats.gap_bytes, 5);
+        assert!(matches!(stream.push(TCP_ACK, seq(10), b"k", 0, &mut stats), Cow::Borrowed(b"k")));
+        // 确认号未越过的缺口继续等待
+        assert!(stream.push(TCP_ACK, seq(14), b"op", 0, &mut stats).is_empty());
+        assert!(stream.push(TCP_ACK, seq(19), b"tu", 0, &mut stats).is_empty());
+        assert_eq!(stream.ack(seq(16), 0, &mut stats), b"op");
+        assert_eq!(stats.gaps, 2);
+        assert_eq!(stream.ack(seq(21), 0, &mut stats), b"tu");
+        assert_eq!(stats.gaps, 3);
+        assert_eq!(stats.gap_bytes, 5 + 3 + 3);
+    }
+
+    #[test]
+    fn ack_past_missing_data_skips_to_the_ack() {
+        let mut stats = ReassemblyStats::default();
+        let mut stream = stream();
+        assert_eq!(&*stream.push(TCP_ACK, seq(0), &mysql_packet(1, b"ab")[..5], 0, &mut stats), [2, 0, 0, 1, b'a']);
+        assert!(stream.mysql.push(&[2, 0, 0, 1, b'a'], false, None).is_empty());
+        // 对端已收到之后的10字节, 不完整的mysql包丢弃
+        assert!(stream.ack(seq(15), 0, &mut stats).is_empty());
+        assert_eq!(stats.gaps, 1);
+        assert_eq!(stats.gap_bytes, 10);
+        let data = mysql_packet(2, b"cd");
+        assert!(matches!(stream.push(TCP_ACK, seq(15), &data, 0, &mut stats), Cow::Borrowed(_)));
+        assert_eq!(stream.mysql.push(&data, false, None)[0].data, data);
+        // 确认号只增加, 旧的确认号不影响
+        assert!(stream.ack(seq(3), 0, &mut stats).is_empty());
+        assert_eq!(stats.gaps, 1);
+    }
+
+    #[test]
+    fn ack_of_fin_is_not_a_gap() {
+        let mut stats = ReassemblyStats::default();
+        let mut stream = stream();
+        assert_eq!(&*stream.push(TCP_ACK | TCP_FIN, seq(0), b"abc", 0, &mut stats), b"abc");
+        assert!(stream.ack(seq(4), 0, &mut stats).is_empty());
+        assert_eq!(stats.gaps, 0);
+    }
+
+    #[test]
+    fn first_ack_sets_the_expected_sequence() {
+        let mut stats = ReassemblyStats::default();
+        let mut stream = TcpStream::new();
+        assert!(stream.ack(100, 0, &mut stats).is_empty());
+        // 之后的数据从确认号开始, 之前丢失的数据在下一个确认号时跳过
+        assert!(stream.push(TCP_ACK, 103, b"def", 0, &mut stats).is_empty());
+        assert_eq!(stream.ack(106, 0, &mut stats), b"def");
+        assert_eq!(stats.gap_bytes, 3);
+    }
+
+    #[test]
+    fn gap_is_skipped_after_waiting_too_long() {
+        let mut stats = ReassemblyStats::default();
+        let mut stream = stream();
+        assert!(stream.push(TCP_ACK, seq(5), b"fgh", 100, &mut stats).is_empty());
+        assert!(stream.push(TCP_ACK, seq(8), b"ij", 100 + MAX_GAP_WAIT_SECS - 1, &mut stats).is_empty());
+        assert_eq!(&*stream.push(TCP_ACK, seq(10), b"k", 100 + MAX_GAP_WAIT_SECS, &mut stats), b"fghijk");
+        assert_eq!(stats.gaps, 1);
+        assert_eq!(stats.gap_bytes, 5);
+    }
+
+    #[test]
+    fn assembler_joins_max_length_physical_packets() {
+        let first = vec![0x03; MAX_PAYLOAD_LEN];
+        let mut data = mysql_packet(0, &first);
+        data.extend(mysql_packet(1, b"tail"));
+        data.extend(mysql_packet(0, &[0x0e]));
+        let mut assembler = MysqlPacketAssembler::new();
+        let mut packets = vec![];
+        // 包头被分段截断
+        for chunk in data.chunks(1447) {
+            packets.extend(assembler.push(chunk, false, None));
+        }
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].data.len(), 4 + MAX_PAYLOAD_LEN + 4);
+        assert_eq!(packets[0].data[3], 1);
+        assert!(packets[0].data[4..4 + MAX_PAYLOAD_LEN].iter().all(|&b| b == 0x03));
+        assert_eq!(&packets[0].data[4 + MAX_PAYLOAD_LEN..], b"tail");
+        assert_eq!(packets[1].data, mysql_packet(0, &[0x0e]));
+    }
+
+    #[test]
+    fn assembler_waits_for_a_split_header() {
+        let data = mysql_packet(0, b"\x03select 1");
+        let mut assembler = MysqlPacketAssembler::new();
+        assert!(assembler.push(&data[..2], false, None).is_empty());
+        assert!(assembler.push(&data[2..6], false, None).is_empty());
+        let packets = assembler.push(&data[6..], false, None);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data, data);
+    }
+
+    #[test]
+    fn assembler_caps_query_text() {
+        // 超过一个物理包的COM_QUERY, 只保留命令字节及前8字节sql
+        let mut query = vec![0x03];
+        query.extend_from_slice(&vec![b'x'; MAX_PAYLOAD_LEN + 10]);
+        let mut data = mysql_packet(0, &query[..MAX_PAYLOAD_LEN]);
+        data.extend(mysql_packet(1, &query[MAX_PAYLOAD_LEN..]));
+        data.extend(mysql_packet(0, b"\x03select 1"));
+        let mut assembler = MysqlPacketAssembler::new();
+        let mut packets = vec![];
+        for chunk in data.chunks(1447) {
+            packets.extend(assembler.push(chunk, false, Some(8)));
+        }
+        assert_eq!(packets.len(), 2);
+        assert_eq!(&packets[0].data[4..], b"\x03xxxxxxxx");
+        assert_eq!(packets[0].len, MAX_PAYLOAD_LEN + 11);
+        assert!(packets[0].truncated);
+        assert_eq!(&packets[1].data[4..], b"\x03select 1");
+        assert!(!packets[1].truncated);
+    }
+
+    #[test]
+    fn assembler_does_not_cap_other_packets() {
+        // 登录包(seq_id为1)、其他命令及返回方向的包不截断
+        let login = mysql_packet(1, &[0x03; 64]);
+        let prepare = mysql_packet(0, b"\x16select 1");
+        let mut assembler = MysqlPacketAssembler::new();
+        let packets = assembler.push(&[&login[..], &prepare[..]].concat(), false, Some(0));
+        assert_eq!(packets[0].data, login);
+        assert_eq!(packets[1].data, prepare);
+        assert!(packets.iter().all(|p| !p.truncated));
+        let response = mysql_packet(1, &[0x03; 64]);
+        let packets = assembler.push(&response, false, None);
+        assert_eq!(packets[0].data, response);
+        // max_sql_len为0时只保留命令字节
+        let packets = assembler.push(&mysql_packet(0, b"\x03select 1"), false, Some(0));
+        assert_eq!(&packets[0].data[4..], b"\x03");
+        assert!(packets[0].truncated);
+    }
+
+    #[test]
+    fn assembler_keeps_first_byte_of_rows() {
+        let mut data = mysql_packet(5, b"\x03abc\x03def");
+        data.extend(mysql_packet(6, &[0xfe, 0, 0, 0x22, 0]));
+        data.extend(mysql_packet(1, b"\x01"));
+        let mut assembler = MysqlPacketAssembler::new();
+        let packets = assembler.push(&data, true, None);
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].data, [8, 0, 0, 5, 3]);
+        assert_eq!(packets[0].len, 8);
+        // 结束包之后不再按行数据处理
+        assert_eq!(packets[1].data, mysql_packet(6, &[0xfe, 0, 0, 0x22, 0]));
+        assert_eq!(packets[2].data, mysql_packet(1, b"\x01"));
+    }
+}
@@ -0,0 +1,239 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/4/16
+*/
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread::{self, JoinHandle};
+use futures::{Future, Stream};
+use futures::future::join_all;
+use futures::sync::mpsc::{channel, Sender, Receiver};
+use pcap::Packet;
+use pcap::tokio::PacketCodec;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::io::{read, write_all};
+use crate::{Config, CaptureStats, open_device, decode_packet, report_due};
+use crate::capture::RawPacket;
+use crate::packet::{StreamPacket, UnixTime};
+use crate::packet::network::LinkType;
+use crate::pipeline::{Dispatcher, Output, QueueStats, OUTPUT_QUEUE_SIZE};
+
+///
+/// 异步模式下审计记录的输出位置
+#[derive(Debug, Clone)]
+pub enum Sink {
+    Stdout,
+    File(String),
+    Tcp(SocketAddr),
+}
+
+impl Sink {
+    ///
+    /// 解析stdout、file:路径、tcp:地址:端口
+    pub fn parse(value: &str) -> Result<Sink, Box<dyn Error>> {
+        if value == "stdout" {
+            return Ok(Sink::Stdout);
+        }
+        if let Some(path) = value.strip_prefix("file:") {
+            return Ok(Sink::File(path.to_string()));
+        }
+        if let Some(addr) = value.strip_prefix("tcp:") {
+            return Ok(Sink::Tcp(addr.parse()?));
+        }
+        Err(format!("unsupported sink: {}", value).into())
+    }
+}
+
+///
+/// 发送审计记录到输出任务的有界队列, 不阻塞调用方
+/// futures的Sender发送时需要可变引用, 每个克隆各自持有一个发送端, 锁只由持有该克隆的线程使用
+#[derive(Debug)]
+pub struct AsyncSender {
+    tx: Mutex<Sender<String>>,
+    stats: Arc<QueueStats>,
+}
+
+impl Clone for AsyncSender {
+    fn clone(&self) -> Self {
+        let tx = self.tx.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        AsyncSender { tx: Mutex::new(tx), stats: self.stats.clone() }
+    }
+}
+
+impl AsyncSender {
+    ///
+    /// 队列满(输出过慢)或输出任务已退出时丢弃并计数
+    pub fn send(&self, line: String) {
+        self.stats.depth.fetch_add(1, Ordering::Relaxed);
+        let mut tx = self.tx.lock().unwrap_or_else(|e| e.into_inner());
+        if tx.try_send(line).is_ok() {
+            self.stats.sent.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+///
+/// pcap数据流的解码, 在事件循环中解析并过滤数据包, 需要处理的包复制后交给dispatcher
+struct PacketDecoder {
+    interface: Arc<str>,
+    link_type: LinkType,
+    conf: Config,
+    stats: Rc<RefCell<CaptureStats>>,
+}
+
+impl PacketCodec for PacketDecoder {
+    type Type = Option<StreamPacket<'static>>;
+
+    fn decode<'p>(&mut self, packet: Packet<'p>) -> Result<Self::Type, pcap::Error> {
+        let mut stats = self.stats.borrow_mut();
+        let packet = RawPacket {
+            ts: UnixTime::new(&packet.header.ts).map_err(|e| pcap::Error::PcapError(e.to_string()))?,
+            len: packet.header.len,
+            data: packet.data,
+        };
+        Ok(decode_packet(&packet, self.link_type, &self.conf, &mut stats).map(|p| {
+            let mut p = p.into_owned();
+            p.interface = Some(self.interface.clone());
+            p
+        }))
+    }
+}
+
+///
+/// 异步监听网卡, 抓包、审计记录输出及监控接口运行在同一个事件循环中
+/// 多线程时抓包在事件循环中进行, 解析仍由worker线程处理
+/// 监听多个网卡时每个网卡一个数据流, 共用同一个dispatcher
+pub fn op_async_run(conf: &Config) -> Result<(), Box<dyn Error>> {
+    if conf.read_file.is_some() {
+        return Err("--async does not support --read-file".into());
+    }
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let mut caps = vec![];
+    for name in &conf.ethernet {
+        let cap = open_device(conf, name)?;
+        let link_type = match LinkType::from_dlt(cap.get_datalink().0) {
+            Some(v) => v,
+            None => return Err(format!("unsupported datalink type: {:?}", cap.get_datalink()).into())
+        };
+        caps.push((Arc::from(name.as_str()), cap, link_type));
+    }
+
+    let (tx, rx) = channel(OUTPUT_QUEUE_SIZE);
+    let sink_stats = Arc::new(QueueStats::default());
+    let sink = conf.sink.clone().unwrap_or(Sink::Stdout);
+    let writer = spawn_sink(&sink, rx, sink_stats.clone(), &handle)?;
+    let output = Output::Async(AsyncSender { tx: Mutex::new(tx), stats: sink_stats.clone() });
+    let dispatcher = Rc::new(RefCell::new(Dispatcher::new(conf, Some(output))?));
+    let stats = Rc::new(RefCell::new(CaptureStats::default()));
+    if let Some(addr) = &conf.metrics_addr {
+        spawn_metrics(addr, &handle, stats.clone(), dispatcher.clone(), sink_stats.clone())?;
+    }
+
+    // 计数信息按所有网卡中最新的包时间输出
+    let last_report = Rc::new(RefCell::new(0));
+    let stats_interval = conf.stats_interval;
+    let mut streams = vec![];
+    for (interface, cap, link_type) in caps {
+        let codec = PacketDecoder { interface, link_type, conf: conf.clone(), stats: stats.clone() };
+        let stream = cap.setnonblock()?.stream(&handle, codec)?;
+        let (dispatcher, stats, sink_stats, last_report) = (dispatcher.clone(), stats.clone(), sink_stats.clone(), last_report.clone());
+        streams.push(stream.for_each(move |packet| {
+            if let Some(packet) = packet {
+                let mut dispatcher = dispatcher.borrow_mut();
+                if report_due(&mut last_report.borrow_mut(), &packet.ts, stats_interval) {
+                    eprintln!("{:?}", stats.borrow());
+                    dispatcher.report();
+                    sink_stats.report("sink");
+                }
+                dispatcher.dispatch(packet);
+            }
+            Ok(())
+        }));
+    }
+    core.run(join_all(streams))?;
+    eprintln!("{:?}", stats.borrow());
+    // 释放事件循环中的任务, 之后只有dispatcher持有输出队列的发送端
+    drop(core);
+    if let Ok(dispatcher) = Rc::try_unwrap(dispatcher) {
+        dispatcher.into_inner().finish();
+        if let Some(writer) = writer {
+            // 等待输出线程写完队列中剩余的审计记录
+            let _ = writer.join();
+        }
+    }
+    Ok(())
+}
+
+///
+/// 启动输出任务, 从队列中读取审计记录写入sink
+/// stdout和文件的写入会阻塞, 由单独的输出线程处理, 不占用事件循环, 返回该线程用于结束时等待写完
+/// tcp在事件循环中异步写入
+fn spawn_sink(sink: &Sink, rx: Receiver<String>, stats: Arc<QueueStats>, handle: &Handle) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
+    let mut out: Box<dyn Write + Send> = match sink {
+        Sink::Stdout => Box::new(io::stdout()),
+        Sink::File(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        Sink::Tcp(addr) => {
+            // 启动时连接, 连接失败直接返回错误
+            let socket = TcpStream::from_stream(std::net::TcpStream::connect(addr)?, handle)?;
+            let task = rx.fold(socket, move |socket, line| {
+                stats.depth.fetch_sub(1, Ordering::Relaxed);
+                write_all(socket, format!("{}\n", line).into_bytes())
+                    .map(|(socket, _)| socket)
+                    .map_err(|e| eprintln!("sink write error: {}", e))
+            }).map(|_| ());
+            handle.spawn(task);
+            return Ok(None);
+        }
+    };
+    let writer = thread::Builder::new().name("sink".to_string()).spawn(move || {
+        for line in rx.wait().flatten() {
+            stats.depth.fetch_sub(1, Ordering::Relaxed);
+            if let Err(e) = writeln!(out, "{}", line) {
+                eprintln!("sink write error: {}", e);
+                break;
+            }
+        }
+    })?;
+    Ok(Some(writer))
+}
+
+///
+/// 在事件循环中提供http监控接口, 任意请求都返回prometheus文本格式的计数信息
+fn spawn_metrics(addr: &SocketAddr, handle: &Handle, stats: Rc<RefCell<CaptureStats>>,
+                 dispatcher: Rc<RefCell<Dispatcher>>, sink_stats: Arc<QueueStats>) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::from_listener(std::net::TcpListener::bind(addr)?, addr, handle)?;
+    let conn_handle = handle.clone();
+    let server = listener.incoming().for_each(move |(socket, _)| {
+        let mut body = String::new();
+        {
+            let stats = stats.borrow();
+            body.push_str(&format!("testaa_packets_total {}\n", stats.packets));
+            body.push_str(&format!("testaa_packets_skipped_total {}\n", stats.skipped));
+            body.push_str(&format!("testaa_packets_malformed_total {}\n", stats.malformed));
+        }
+        dispatcher.borrow_mut().metrics(&mut body);
+        sink_stats.metrics("sink", &mut body);
+        let response = format!("HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                               body.len(), body);
+        // 先读取请求再返回, 避免未读取的数据导致连接被重置
+        let reply = read(socket, vec![0; 4096])
+            .and_then(move |(socket, _, _)| write_all(socket, response.into_bytes()))
+            .map(|_| ())
+            .map_err(|_| ());
+        conn_handle.spawn(reply);
+        Ok(())
+    }).map_err(|e| eprintln!("metrics error: {}", e));
+    handle.spawn(server);
+    Ok(())
+}
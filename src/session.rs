@@ -2,101 +2,143 @@
 @author: xiao cai niao
 @datetime: 2020/3/28
 */
-use std::io::{Seek};
-use std::io;
 use std;
-use byteorder::{ReadBytesExt};
 use crate::packet::{MysqlProtocol, StreamPacket, StreamType};
 use crate::packet::UnixTime;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
 use std::error::Error;
-use std::str::from_utf8;
-use crate::Tell;
+use crate::packet::network::{TCP_SYN, TCP_ACK, TCP_FIN, TCP_RST};
+use crate::lru::LruIndex;
+use crate::flow::FlowKey;
+use crate::pipeline::Output;
+use crate::packet::protocol::{ServerHandshake, HandshakeResponse, OkPacket, MAX_PAYLOAD_LEN};
 
 ///
 /// 记录session ip端口信息
 #[derive(Debug, Clone)]
 pub struct SessionHostInfo{
-    pub source: String,
-    pub destination: String,
+    pub source: IpAddr,
+    pub destination: IpAddr,
     pub source_port: u16,
-    pub destination_port: u16
+    pub destination_port: u16,
+    pub server: Option<SocketAddr>              // 镜像模式下匹配到的服务端
 }
 
 impl SessionHostInfo{
     pub fn new() -> SessionHostInfo{
         SessionHostInfo{
-            source: "".to_string(),
-            destination: "".to_string(),
+            source: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            destination: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             source_port: 0,
-            destination_port: 0
+            destination_port: 0,
+            server: None
         }
     }
-    pub fn set(&mut self, source: String, destination: String, source_port: u16, destionation_port: u16) {
-        self.source = source.clone();
-        self.destination = destination.clone();
-        self.source_port = source_port.clone();
-        self.destination_port = destionation_port.clone();
+    pub fn set(&mut self, source: IpAddr, destination: IpAddr, source_port: u16, destionation_port: u16) {
+        self.source = source;
+        self.destination = destination;
+        self.source_port = source_port;
+        self.destination_port = destionation_port;
     }
 }
 
+///
+/// 结果集解析状态, 结果集中的包不能根据首字节判断包类型
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResultState{
+    Null,                           // 不在结果集中
+    ColumnDefinition(u64),          // 剩余的列定义包数量
+    Rows(bool),                     // 行数据, true表示列定义后的第一个包, 可能为列定义结束的EOF包
+}
+
 ///
 /// 该结构记录client一次请求到结束的流程
 ///
 #[derive(Clone, Debug)]
 pub struct SessionInfo{
-    pub source: String,                         // 源地址
-    pub destination: String,                    // 目标地址
+    pub source: IpAddr,                         // 源地址
+    pub destination: IpAddr,                    // 目标地址
     pub source_port: u16,                       // 源端口
     pub destination_port: u16,                  // 目标端口
+    pub vlan_id: Option<u16>,                   // vlan id, QinQ时为内层id
+    pub outer_vlan_id: Option<u16>,             // QinQ时外层的vlan id
+    pub interface: Option<Arc<str>>,            // 抓取到请求的网卡
+    pub server: Option<SocketAddr>,             // 镜像模式下处理该请求的服务端
+    pub handshake: Option<Arc<ServerHandshake>>,    // 连接的服务端握手信息, 输出时从连接信息中获取
+    pub handshake_response: Option<Arc<HandshakeResponse>>, // 客户端回复的握手包, 包含连接属性, 输出时从连接信息中获取
     pub client_request: MysqlProtocol,          // 请求协议类型
     pub server_response: MysqlProtocol,         // 返回协议类型
     pub user_name: String,                      // 连接使用的用户名
+    pub database: String,                       // 执行请求时所在的库
+    pub new_database: Option<String>,           // 请求成功后切换到的库, 来自COM_INIT_DB、USE及SESSION_TRACK_SCHEMA
+    pub thread_id: Option<u32>,                 // 服务端的连接id
+    pub login_time: Option<UnixTime>,           // 连接登录的时间
     pub create_conn_auth: bool,                 // 是否已接收到创建连接所使用的验证信息
     pub execute_sql: String,                    // 执行的请求语句
+    pub sql_truncated: bool,                    // 请求语句超过保留上限已被截断
     pub response_value: String,                 // 返回的情况
+    pub ok_packet: Option<OkPacket>,            // 返回的OK包, 包含影响行数、gtid等, 多个结果集时合并
     pub connection_pre: bool,                   // 准备建立连接
     pub seq_id: u8,                             // 当前包的seq_id
+    pub result_state: ResultState,              // 结果集解析状态
+    pub more_results: bool,                     // 结束包中带有SERVER_MORE_RESULTS_EXISTS, 还有后续结果集
     pub start_time: UnixTime,                   // 开始时间
     pub end_time: UnixTime,                     // 结束时间
     pub is_ok: bool,                            // 是否为需要的包， 不需要的不会插入
+    pub incomplete: bool,                       // 未等到返回即被淘汰或连接已断开
 }
 
 impl SessionInfo{
     pub fn new(stream_packet: &mut StreamPacket) -> Result<SessionInfo, Box<dyn Error>>{
         Ok(SessionInfo{
-            source: stream_packet.session_host_info.source.clone(),
-            destination: stream_packet.session_host_info.destination.clone(),
+            source: stream_packet.session_host_info.source,
+            destination: stream_packet.session_host_info.destination,
             source_port: stream_packet.session_host_info.source_port.clone(),
             destination_port: stream_packet.session_host_info.destination_port.clone(),
+            vlan_id: stream_packet.vlan_id,
+            outer_vlan_id: stream_packet.outer_vlan_id,
+            interface: stream_packet.interface.clone(),
+            server: stream_packet.session_host_info.server,
+            handshake: None,
+            handshake_response: None,
             client_request: MysqlProtocol::Null,
             server_response: MysqlProtocol::Null,
             user_name: "".to_string(),
+            database: "".to_string(),
+            new_database: None,
+            thread_id: None,
+            login_time: None,
             create_conn_auth: false,
             execute_sql: "".to_string(),
+            sql_truncated: false,
             response_value: "".to_string(),
+            ok_packet: None,
             connection_pre: false,
             seq_id: stream_packet.protocol_header.seq_id.clone(),
+            result_state: ResultState::Null,
+            more_results: false,
             start_time: stream_packet.ts.clone(),
             end_time: UnixTime{ tv_sec: 0, tv_usec: 0 },
-            is_ok: false
+            is_ok: false,
+            incomplete: false
         })
     }
 
     ///
     /// 解包并写入或清除
-    pub fn session_unpacket(&mut self, stream_packet: &mut StreamPacket, session_key: &String, all_session: &mut AllSessionInfo) -> Result<(), Box<dyn Error>> {
-        //let mut local_session = self.clone();   //复制一个全新的session， 用于可变
+    pub fn session_unpacket(mut self, stream_packet: &mut StreamPacket, session_key: &FlowKey, all_session: &mut AllSessionInfo) -> Result<(), Box<dyn Error>> {
         let protocol_type = stream_packet.protocol_header.protocol_type.clone();
-        protocol_type.protocol_unpacket(stream_packet, self)?;
+        protocol_type.protocol_unpacket(stream_packet, &mut self)?;
         match stream_packet.s_type{
             StreamType::Request => {
                 //插入session缓存
-                self.insert(all_session, session_key)?;
+                self.insert(all_session, session_key);
             }
             StreamType::Response => {
                 //打印并删除
-                self.out_info();
+                all_session.write_session(session_key, &mut self);
                 all_session.remove(session_key);
             }
         }
@@ -104,69 +146,569 @@ impl SessionInfo{
     }
 
     ///
-    /// 操作client创建链接时回的handshake包， 从中获取user_name
-    /// 如果数据包id不为顺序表示存在问题，返回false，替换该session
+    /// 解析请求对应的返回包, 结果集及存在后续结果的情况需要等到最后的结束包
+    /// 返回整个返回是否已结束, 结束时由调用方打印并从session表中删除
+    pub fn response_unpacket(&mut self, stream_packet: &mut StreamPacket) -> Result<bool, Box<dyn Error>> {
+        self.seq_id = stream_packet.protocol_header.seq_id;
+        let complete = match self.result_state {
+            ResultState::Null => {
+                let protocol_type = stream_packet.protocol_header.protocol_type.clone();
+                protocol_type.protocol_unpacket(stream_packet, self)?;
+                self.result_state == ResultState::Null && !self.more_results
+            }
+            _ => self.unpacket_result_set(stream_packet)?
+        };
+        Ok(complete)
+    }
+
+    ///
+    /// 解析结果集中列定义之后的包, 返回整个返回是否已结束
+    /// 列定义结束的EOF包payload固定为5字节, 结束包为EOF包或0xfe开头的OK包
+    /// 行数据以0xfe开头时第一列长度至少为2^24, 包长度为MAX_PAYLOAD_LEN, OK包带有session状态变化时可能超过9字节
+    fn unpacket_result_set(&mut self, stream_packet: &mut StreamPacket) -> Result<bool, Box<dyn Error>> {
+        let payload = stream_packet.protocol_header.payload;
+        let code = stream_packet.data_cur.get_ref()[4];
+        match self.result_state {
+            ResultState::ColumnDefinition(remaining) => {
+                self.result_state = match remaining {
+                    0 | 1 => ResultState::Rows(true),
+                    _ => ResultState::ColumnDefinition(remaining - 1)
+                };
+                Ok(false)
+            }
+            ResultState::Rows(first) => {
+                if code == 0xfe && (payload as usize) < MAX_PAYLOAD_LEN {
+                    if first && payload == 5 {
+                        // 未开启CLIENT_DEPRECATE_EOF时列定义之后的EOF包
+                        self.result_state = ResultState::Rows(false);
+                        return Ok(false);
+                    }
+                    self.result_state = ResultState::Null;
+                    if payload == 5 {
+                        MysqlProtocol::EOFPacket.protocol_unpacket(stream_packet, self)?;
+                    } else {
+                        MysqlProtocol::OKPacket.protocol_unpacket(stream_packet, self)?;
+                    }
+                    return Ok(!self.more_results);
+                }
+                if code == 0xff {
+                    self.result_state = ResultState::Null;
+                    MysqlProtocol::ERRpacket.protocol_unpacket(stream_packet, self)?;
+                    return Ok(true);
+                }
+                self.result_state = ResultState::Rows(false);
+                Ok(false)
+            }
+            ResultState::Null => Ok(true)
+        }
+    }
+
+    ///
+    /// 操作client创建链接时回的handshake包， 从中获取user_name及连接属性
+    /// 如果数据包id不为顺序或无法解析表示存在问题，返回false，替换该session
+    /// SSLRequest之后的内容已加密, 同样返回false
     pub fn unpacket_handshake_response(&mut self, stream_packet: &mut StreamPacket) -> std::result::Result<bool, Box<dyn Error>>{
-        if stream_packet.len <= 102{
+        if stream_packet.protocol_header.seq_id != self.seq_id.wrapping_add(1){
+            // 不是紧跟握手包的回包, 无法确认为登录请求
             return Ok(false);
         }
-        if stream_packet.protocol_header.seq_id == self.seq_id + 1{
-            stream_packet.data_cur.seek(io::SeekFrom::Current(31))?;
-            let mut user_name_packet: Vec<u8> = vec![];
-            loop {
-                let a = stream_packet.data_cur.read_u8()?;
-                if &a == &0x00 {
-                    break;
-                } else {
-                    user_name_packet.push(a);
-                }
-            }
-            let user_name = from_utf8(user_name_packet.as_ref())?;
-            self.user_name = user_name.parse()?;
-            self.create_conn_auth = true;
+        stream_packet.data_cur.set_position(4);
+        let response = match HandshakeResponse::parse(&mut stream_packet.data_cur){
+            Ok(v) => v,
+            Err(_) => return Ok(false)
+        };
+        let ssl = response.ssl;
+        self.user_name = response.user_name.clone();
+        self.handshake_response = Some(Arc::new(response));
+        if ssl{
+            return Ok(false);
         }
+        self.create_conn_auth = true;
         Ok(true)
     }
 
-    pub fn insert(&self, all_session_info: &mut AllSessionInfo, session_key: &String) -> std::result::Result<(), Box<dyn Error>> {
+    pub fn insert(self, all_session_info: &mut AllSessionInfo, session_key: &FlowKey) {
         if self.is_ok{
-            all_session_info.aluino.insert(session_key.parse()?, self.clone());
+            all_session_info.insert_session(session_key, self);
         }
-        Ok(())
     }
 
     ///
     /// 输出信息
-    pub fn out_info(&self) {
-        println!("{:?}", self);
+    pub fn out_info(&self, output: &Output) {
+        output.write(self);
+    }
+}
+
+///
+/// 连接断开的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectReason{
+    Fin,
+    Rst,
+    ComQuit,
+    Evicted,                                    // 空闲超时或超过容量被淘汰
+}
+
+///
+/// 连接事件类型
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEventType{
+    Connect,
+    Disconnect,
+}
+
+///
+/// 连接建立/断开的审计事件
+#[derive(Debug, Clone)]
+pub struct ConnectionEvent{
+    pub event_type: ConnectionEventType,
+    pub source: IpAddr,                         // 客户端地址
+    pub destination: IpAddr,                    // 服务端地址
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub vlan_id: Option<u16>,
+    pub interface: Option<Arc<str>>,
+    pub server: Option<SocketAddr>,             // 镜像模式下的服务端
+    pub handshake: Option<Arc<ServerHandshake>>,
+    pub handshake_response: Option<Arc<HandshakeResponse>>,
+    pub user_name: String,
+    pub time: UnixTime,                         // 事件发生的时间
+    pub reason: Option<DisconnectReason>,       // 断开原因, 只有断开事件有值
+    pub duration: Option<f64>,                  // 连接持续的秒数, 只有断开事件有值
+}
+
+impl ConnectionEvent{
+    ///
+    /// 输出信息
+    pub fn out_info(&self, output: &Output) {
+        output.write(self);
     }
 }
 
 ///
 /// 存放每个连接与用户的对应关系
-#[derive(Debug)]
+/// 由syn/syn-ack或第一个数据包创建, fin/rst后删除
+#[derive(Debug, Clone)]
 pub struct Connection{
-    pub host: String,
-    pub port: u16,
-    pub user_name: String
+    pub host: IpAddr,                           // 客户端地址
+    pub port: u16,                              // 客户端端口
+    pub server: IpAddr,                         // 服务端地址
+    pub server_port: u16,                       // 服务端端口
+    pub vlan_id: Option<u16>,
+    pub interface: Option<Arc<str>>,            // 最先见到该连接的网卡
+    pub mirror_server: Option<SocketAddr>,      // 镜像模式下的服务端
+    pub handshake: Option<Arc<ServerHandshake>>,    // 服务端发送的握手包
+    pub handshake_response: Option<Arc<HandshakeResponse>>,   // 客户端回复的握手包, 包含连接属性
+    pub user_name: String,                      // 登录用户
+    pub database: String,                       // 当前所在的库, 登录时为初始库, 之后随切换库的请求更新
+    pub thread_id: Option<u32>,                 // 服务端握手包中的连接id
+    pub login_time: Option<UnixTime>,           // 收到客户端握手包的时间
+    pub start_time: UnixTime,                   // 见到syn的时间, 未见到syn时为第一个包的时间
+    pub established: bool,                      // 已见到syn-ack或数据包
+    pub connect_pending: Option<UnixTime>,      // 见到syn-ack的时间, 连接事件等到客户端回复握手包后输出
+    pub client_fin: bool,                       // 客户端已发送fin
+    pub server_fin: bool,                       // 服务端已发送fin
+    pub closed: bool,                           // 已输出断开事件
+}
+
+impl Connection{
+    pub fn new(stream_packet: &StreamPacket) -> Connection{
+        Connection{
+            host: stream_packet.session_host_info.source,
+            port: stream_packet.session_host_info.source_port,
+            server: stream_packet.session_host_info.destination,
+            server_port: stream_packet.session_host_info.destination_port,
+            vlan_id: stream_packet.vlan_id,
+            interface: stream_packet.interface.clone(),
+            mirror_server: stream_packet.session_host_info.server,
+            handshake: None,
+            handshake_response: None,
+            user_name: "".to_string(),
+            database: "".to_string(),
+            thread_id: None,
+            login_time: None,
+            start_time: stream_packet.ts.clone(),
+            established: false,
+            connect_pending: None,
+            client_fin: false,
+            server_fin: false,
+            closed: false
+        }
+    }
+
+    ///
+    /// 生成连接事件
+    pub fn event(&self, event_type: ConnectionEventType, time: &UnixTime, reason: Option<DisconnectReason>) -> ConnectionEvent{
+        let duration = match event_type{
+            ConnectionEventType::Disconnect => Some(time.seconds_since(&self.start_time)),
+            ConnectionEventType::Connect => None
+        };
+        ConnectionEvent{
+            event_type,
+            source: self.host,
+            destination: self.server,
+            source_port: self.port,
+            destination_port: self.server_port,
+            vlan_id: self.vlan_id,
+            interface: self.interface.clone(),
+            server: self.mirror_server,
+            handshake: self.handshake.clone(),
+            handshake_response: self.handshake_response.clone(),
+            user_name: self.user_name.clone(),
+            time: time.clone(),
+            reason,
+            duration
+        }
+    }
+
+    ///
+    /// 登录后根据客户端握手包记录用户、初始库及登录时间
+    pub fn login(&mut self, handshake_response: Arc<HandshakeResponse>, ts: &UnixTime){
+        self.user_name = handshake_response.user_name.clone();
+        self.database = handshake_response.database.clone();
+        self.login_time = Some(ts.clone());
+        self.handshake_response = Some(handshake_response);
+    }
+
+    ///
+    /// 用连接信息补全该连接上的请求记录, 请求本身已有的值不覆盖
+    pub fn fill_session(&self, session_info: &mut SessionInfo){
+        if session_info.handshake.is_none(){
+            session_info.handshake = self.handshake.clone();
+        }
+        if session_info.handshake_response.is_none(){
+            session_info.handshake_response = self.handshake_response.clone();
+        }
+        if session_info.user_name.is_empty(){
+            session_info.user_name = self.user_name.clone();
+        }
+        if session_info.database.is_empty(){
+            session_info.database = self.database.clone();
+        }
+        if session_info.thread_id.is_none(){
+            session_info.thread_id = self.thread_id;
+        }
+        if session_info.login_time.is_none(){
+            session_info.login_time = self.login_time.clone();
+        }
+    }
+
+    ///
+    /// 输出等待中的连接事件, 断开前未收到客户端握手包时只有地址信息
+    pub fn write_connect(&mut self, output: &Output){
+        if let Some(ts) = self.connect_pending.take(){
+            self.event(ConnectionEventType::Connect, &ts, None).out_info(output);
+        }
+    }
+}
+
+///
+/// session表及连接表的容量和空闲超时配置
+#[derive(Debug, Clone)]
+pub struct SessionLimits{
+    pub max_entries: usize,                     // 每个表最多保存的条目数, 超过时淘汰最久未访问的条目
+    pub idle_timeout: u64,                      // 请求等待返回的超时秒数
+    pub connection_idle_timeout: u64,           // 连接空闲的超时秒数
+}
+
+///
+/// 表大小及淘汰计数
+#[derive(Debug, Default, Clone)]
+pub struct SessionStats{
+    pub sessions: usize,                        // 等待返回的请求数
+    pub pipelined: usize,                       // 排队的请求数
+    pub connections: usize,                     // 跟踪中的连接数
+    pub evicted_idle: u64,                      // 空闲超时淘汰的条目数
+    pub evicted_lru: u64,                       // 超过容量淘汰的条目数
 }
 
 ///
 /// 记录所有客户端操作流程信息， 已源ip:port作为唯一键
 /// 一个会话操作结束会删除对应信息
 ///
+/// 前一个请求还未返回时发送的请求(pipeline)放入pipelined中排队, 按顺序对应返回
+///
+/// connections记录tcp连接的生命周期及登录信息, 输出请求时用于补全用户、库等信息, 连接断开时删除该连接的所有信息
+///
+/// 请求和连接都按最近访问顺序记录, 空闲超时或超过容量时淘汰并作为未完成的记录输出
+///
 #[derive(Debug)]
 pub struct AllSessionInfo {
-    pub aluino: HashMap<String, SessionInfo>,
-    pub connections: HashMap<String, Connection>
+    pub aluino: HashMap<FlowKey, SessionInfo>,
+    pub pipelined: HashMap<FlowKey, VecDeque<SessionInfo>>,
+    pub connections: HashMap<FlowKey, Connection>,
+    pub stats: SessionStats,
+    pub output: Output,                         // 审计记录的输出位置
+    limits: SessionLimits,
+    session_lru: LruIndex<FlowKey>,
+    connection_lru: LruIndex<FlowKey>,
+    now: u64,                                   // 最近一个包的时间秒
 }
 impl AllSessionInfo{
-    pub fn new() -> AllSessionInfo{
-        AllSessionInfo{ aluino: HashMap::new(), connections: HashMap::new() }
+    pub fn new(limits: SessionLimits, output: Output) -> AllSessionInfo{
+        AllSessionInfo{
+            aluino: HashMap::new(),
+            pipelined: HashMap::new(),
+            connections: HashMap::new(),
+            stats: SessionStats::default(),
+            output,
+            limits,
+            session_lru: LruIndex::new(),
+            connection_lru: LruIndex::new(),
+            now: 0
+        }
+    }
+
+    ///
+    /// 写入session, 超过容量时淘汰最久未访问的session
+    pub fn insert_session(&mut self, session_key: &FlowKey, session_info: SessionInfo){
+        if !self.aluino.contains_key(session_key) && self.aluino.len() >= self.limits.max_entries{
+            if let Some(key) = self.session_lru.pop_oldest(){
+                self.stats.evicted_lru += 1;
+                self.evict_session(&key);
+            }
+        }
+        self.session_lru.touch(session_key, self.now);
+        self.aluino.insert(*session_key, session_info);
+    }
+
+    ///
+    /// 返回包所属的请求是否正在接收结果集的行数据
+    pub fn receiving_rows(&self, s_type: &StreamType, session_key: &FlowKey) -> bool{
+        if let StreamType::Response = s_type{
+            if let Some(v) = self.aluino.get(session_key){
+                return matches!(v.result_state, ResultState::Rows(_));
+            }
+        }
+        false
+    }
+
+    ///
+    /// 获取session用于直接修改, 同时记录一次访问
+    pub fn get_mut(&mut self, session_key: &FlowKey) -> Option<&mut SessionInfo>{
+        let session_info = self.aluino.get_mut(session_key)?;
+        self.session_lru.touch(session_key, self.now);
+        Some(session_info)
     }
 
-    pub fn remove(&mut self, session_key: &String){
+    pub fn remove(&mut self, session_key: &FlowKey){
         self.aluino.remove(session_key);
+        self.session_lru.remove(session_key);
+    }
+
+    ///
+    /// 请求已结束, 打印并删除, 如果有排队的请求则作为当前请求
+    pub fn complete(&mut self, session_key: &FlowKey){
+        self.finish(session_key, false);
+    }
+
+    ///
+    /// 返回方向跳过了缺口, 当前请求的返回已无法解析, 作为未完成的记录输出, 如果有排队的请求则作为当前请求
+    pub fn abandon(&mut self, session_key: &FlowKey){
+        self.finish(session_key, true);
+    }
+
+    fn finish(&mut self, session_key: &FlowKey, incomplete: bool){
+        self.session_lru.remove(session_key);
+        if let Some(mut session_info) = self.aluino.remove(session_key){
+            session_info.incomplete |= incomplete;
+            self.write_session(session_key, &mut session_info);
+        }
+        if let Some(queue) = self.pipelined.get_mut(session_key){
+            let next = queue.pop_front();
+            if queue.is_empty(){
+                self.pipelined.remove(session_key);
+            }
+            if let Some(next) = next{
+                self.insert_session(session_key, next);
+            }
+        }
+    }
+
+    ///
+    /// 删除session及排队的请求, 未结束的请求作为未完成的记录输出
+    fn evict_session(&mut self, session_key: &FlowKey){
+        self.session_lru.remove(session_key);
+        let mut evicted: Vec<SessionInfo> = vec![];
+        if let Some(v) = self.aluino.remove(session_key){
+            evicted.push(v);
+        }
+        if let Some(queue) = self.pipelined.remove(session_key){
+            evicted.extend(queue);
+        }
+        for mut session_info in evicted{
+            session_info.incomplete = true;
+            self.write_session(session_key, &mut session_info);
+        }
+    }
+
+    ///
+    /// 输出session, 加上所属连接的用户、库及握手信息
+    /// 同一连接上的请求按顺序执行, 输出时连接所在的库即该请求执行时的库, 请求成功切换库后再更新连接
+    pub fn write_session(&mut self, session_key: &FlowKey, session_info: &mut SessionInfo){
+        if !matches!(session_info.server_response, MysqlProtocol::OKPacket){
+            // 请求失败或未等到返回, 没有切换库
+            session_info.new_database = None;
+        }
+        if let Some(connection) = self.connections.get_mut(session_key){
+            connection.fill_session(session_info);
+            if let Some(database) = &session_info.new_database{
+                connection.database = database.clone();
+            }
+        }
+        session_info.out_info(&self.output);
+    }
+
+    ///
+    /// 记录连接的服务端握手信息
+    pub fn set_handshake(&mut self, session_key: &FlowKey, handshake: Arc<ServerHandshake>){
+        if let Some(connection) = self.connections.get_mut(session_key){
+            connection.thread_id = Some(handshake.thread_id);
+            connection.handshake = Some(handshake);
+        }
+    }
+
+    ///
+    /// 删除连接信息, 未输出断开事件的连接输出淘汰事件
+    fn evict_connection(&mut self, session_key: &FlowKey){
+        self.connection_lru.remove(session_key);
+        if let Some(mut connection) = self.connections.remove(session_key){
+            connection.write_connect(&self.output);
+            if connection.established && !connection.closed{
+                let ts = UnixTime{ tv_sec: self.now, tv_usec: 0 };
+                connection.event(ConnectionEventType::Disconnect, &ts, Some(DisconnectReason::Evicted)).out_info(&self.output);
+            }
+        }
+    }
+
+    ///
+    /// 根据包时间淘汰空闲超时的session和连接, 每秒最多检查一次
+    pub fn expire(&mut self, ts: &UnixTime){
+        if ts.tv_sec <= self.now{
+            return;
+        }
+        self.now = ts.tv_sec;
+        for key in self.session_lru.pop_idle(self.now, self.limits.idle_timeout){
+            self.stats.evicted_idle += 1;
+            self.evict_session(&key);
+        }
+        for key in self.connection_lru.pop_idle(self.now, self.limits.connection_idle_timeout){
+            self.stats.evicted_idle += 1;
+            self.evict_connection(&key);
+        }
+    }
+
+    ///
+    /// 当前各表的大小及淘汰计数
+    pub fn table_stats(&mut self) -> SessionStats{
+        self.stats.sessions = self.aluino.len();
+        self.stats.pipelined = self.pipelined.values().map(|v| v.len()).sum();
+        self.stats.connections = self.connections.len();
+        self.stats.clone()
+    }
+
+    ///
+    /// 记录客户端回复的握手包并输出连接事件
+    pub fn connection_login(&mut self, session_key: &FlowKey, handshake_response: Arc<HandshakeResponse>, ts: &UnixTime){
+        if let Some(connection) = self.connections.get_mut(session_key){
+            connection.login(handshake_response, ts);
+            connection.write_connect(&self.output);
+        }
+    }
+
+    ///
+    /// 客户端已开始发送请求, 没有见到握手包(如丢包)时同样输出连接事件
+    pub fn connection_request(&mut self, session_key: &FlowKey){
+        if let Some(connection) = self.connections.get_mut(session_key){
+            connection.write_connect(&self.output);
+        }
+    }
+
+    ///
+    /// 根据syn/syn-ack及数据包创建连接信息, 见到syn-ack时记录连接事件, 收到客户端握手包或断开时输出
+    pub fn connection_open(&mut self, stream_packet: &StreamPacket, session_key: &FlowKey){
+        let flag = stream_packet.packet_flag;
+        if flag & (TCP_FIN | TCP_RST) != 0 && stream_packet.payload.is_empty(){
+            return;
+        }
+        if !self.connections.contains_key(session_key) && self.connections.len() >= self.limits.max_entries{
+            if let Some(key) = self.connection_lru.pop_oldest(){
+                self.stats.evicted_lru += 1;
+                self.evict_connection(&key);
+            }
+        }
+        self.connection_lru.touch(session_key, self.now);
+        if flag & TCP_SYN != 0 && flag & TCP_ACK == 0{
+            // 客户端发起新连接, 端口复用时覆盖旧的连接信息
+            self.connections.insert(*session_key, Connection::new(stream_packet));
+            return;
+        }
+        let connection = self.connections.entry(*session_key)
+            .or_insert_with(|| Connection::new(stream_packet));
+        if connection.established{
+            return;
+        }
+        connection.established = true;
+        if flag & TCP_SYN != 0{
+            connection.connect_pending = Some(stream_packet.ts.clone());
+        }
+    }
+
+    ///
+    /// 处理fin/rst, 第一个fin或rst时输出断开事件, 双方都发送fin或rst后删除连接相关的所有信息
+    /// 返回连接是否已删除
+    pub fn connection_close(&mut self, stream_packet: &StreamPacket, session_key: &FlowKey) -> bool{
+        let flag = stream_packet.packet_flag;
+        if flag & (TCP_FIN | TCP_RST) == 0{
+            return false;
+        }
+        let connection = match self.connections.get_mut(session_key){
+            Some(v) => v,
+            None => return false
+        };
+        let reason = if flag & TCP_RST != 0 {
+            DisconnectReason::Rst
+        } else {
+            match stream_packet.s_type{
+                StreamType::Request => connection.client_fin = true,
+                StreamType::Response => connection.server_fin = true
+            }
+            DisconnectReason::Fin
+        };
+        connection.write_connect(&self.output);
+        if !connection.closed && connection.established{
+            connection.event(ConnectionEventType::Disconnect, &stream_packet.ts, Some(reason.clone())).out_info(&self.output);
+        }
+        connection.closed = true;
+        if reason == DisconnectReason::Rst || (connection.client_fin && connection.server_fin){
+            // 先输出未完成的请求, 此时还能获取连接信息
+            self.evict_session(session_key);
+            self.connections.remove(session_key);
+            self.connection_lru.remove(session_key);
+            return true;
+        }
+        false
+    }
+
+    ///
+    /// 客户端发送COM_QUIT, 输出断开事件, 连接信息在fin/rst后删除
+    pub fn connection_quit(&mut self, session_key: &FlowKey, ts: &UnixTime){
+        if let Some(connection) = self.connections.get_mut(session_key){
+            connection.write_connect(&self.output);
+            if !connection.closed{
+                connection.event(ConnectionEventType::Disconnect, ts, Some(DisconnectReason::ComQuit)).out_info(&self.output);
+                connection.closed = true;
+            }
+        }
+    }
+
+    ///
+    /// 当前请求还在等待返回时, 后续请求排队
+    pub fn push_pipelined(&mut self, session_key: &FlowKey, session_info: SessionInfo){
+        if session_info.is_ok{
+            self.pipelined.entry(*session_key).or_default().push_back(session_info);
+        }
     }
 
 }